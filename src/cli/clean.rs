@@ -1,26 +1,50 @@
-use std::process::ExitCode;
+use std::{path::Path, process::ExitCode, time::Duration};
 
 use anyhow::Result;
 use clap::Parser;
-use kaledis_dalbit::polyfill;
+use kaledis_dalbit::{manifest::Manifest, polyfill};
 use url::Url;
 
+use super::DEFAULT_MANIFEST_PATH;
+
 /// Clean dalbit polyfills cache
 #[derive(Debug, Clone, Parser)]
 pub struct CleanCommand {
+    #[arg(conflicts_with_all = ["unused", "older_than"])]
     repo: Option<Url>,
+    /// Only clean caches that no known manifest references
+    #[arg(long)]
+    unused: bool,
+    /// Only clean unreferenced caches that have not been used for this many days
+    #[arg(long, value_name = "DAYS")]
+    older_than: Option<u64>,
 }
 
 impl CleanCommand {
     pub async fn run(self) -> Result<ExitCode> {
+        let cache_dir = if Path::new(DEFAULT_MANIFEST_PATH).exists() {
+            let manifest = Manifest::from_file(DEFAULT_MANIFEST_PATH).await?;
+            manifest.polyfill().cache_dir()?
+        } else {
+            polyfill::cache_dir()?
+        };
+
         if let Some(repo) = self.repo {
-            polyfill::clean_cache(&repo).await?;
+            polyfill::clean_cache(&cache_dir, &repo).await?;
+        } else if self.unused || self.older_than.is_some() {
+            let older_than = self
+                .older_than
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60));
+            let removed = polyfill::clean_cache_unused(&cache_dir, older_than).await?;
+            for path in &removed {
+                println!("Removed {}", path.display());
+            }
         } else {
-            polyfill::clean_cache_all().await?;
+            polyfill::clean_cache_all(&cache_dir).await?;
         }
 
         println!("Successfully cleaned polyfill caches");
 
-        return Ok(ExitCode::SUCCESS);
+        Ok(ExitCode::SUCCESS)
     }
 }
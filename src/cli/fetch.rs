@@ -1,24 +1,59 @@
-use std::process::ExitCode;
+use std::{path::Path, process::ExitCode};
 
 use anyhow::Result;
 use clap::Parser;
-use kaledis_dalbit::manifest::Manifest;
+use kaledis_dalbit::{manifest::Manifest, polyfill};
 
 use super::DEFAULT_MANIFEST_PATH;
 
-/// Fetch dalbit polyfills
 /// Fetch dalbit polyfills
 #[derive(Debug, Clone, Parser)]
-pub struct FetchCommand {}
+pub struct FetchCommand {
+    /// Report changes without updating polyfills
+    #[arg(long)]
+    dry_run: bool,
+}
 
 impl FetchCommand {
     pub async fn run(self) -> Result<ExitCode> {
         let manifest = Manifest::from_file(DEFAULT_MANIFEST_PATH).await?;
-        let polyfill_cache = manifest.polyfill().cache().await?;
-        polyfill_cache.fetch()?;
+        polyfill::add_references(Path::new(DEFAULT_MANIFEST_PATH), manifest.polyfill()).await?;
+        let polyfill = manifest.polyfill();
+        let polyfill_layers = polyfill.caches(manifest.target_version()).await?;
+        let summaries = polyfill_layers.fetch(self.dry_run)?;
+
+        for (source, summary) in polyfill.sources().iter().zip(summaries) {
+            let Some(summary) = summary else {
+                println!("{} is a local polyfill, skipped", source);
+                continue;
+            };
+            if !summary.is_changed() {
+                println!("{} is already up to date ({})", source, summary.new_commit);
+                continue;
+            }
 
-        // TO-DO: Is fetched polyfill already latest version?
+            if self.dry_run {
+                println!(
+                    "{} can be updated: {} -> {}",
+                    source, summary.old_commit, summary.new_commit
+                );
+            } else {
+                println!(
+                    "{} updated: {} -> {}",
+                    source, summary.old_commit, summary.new_commit
+                );
+            }
+            for commit in &summary.commits {
+                println!("  {}", commit);
+            }
+            if !summary.added_exports.is_empty() {
+                println!("  added exports: {}", summary.added_exports.join(", "));
+            }
+            if !summary.removed_exports.is_empty() {
+                println!("  removed exports: {}", summary.removed_exports.join(", "));
+            }
+        }
 
-        return Ok(ExitCode::SUCCESS);
+        Ok(ExitCode::SUCCESS)
     }
 }
@@ -21,6 +21,6 @@ impl InitCommand {
             println!("Initialized dalbit manifest");
         }
 
-        return Ok(ExitCode::SUCCESS);
+        Ok(ExitCode::SUCCESS)
     }
 }
@@ -1,4 +1,4 @@
-use std::process::ExitCode;
+use std::{path::PathBuf, process::ExitCode};
 
 use anyhow::Result;
 use clap::{Args, Parser, Subcommand};
@@ -6,13 +6,17 @@ use clap::{Args, Parser, Subcommand};
 mod clean;
 mod fetch;
 mod init;
+mod polyfill;
 mod transpile;
+mod vendor;
 
 use clean::CleanCommand;
 use fetch::FetchCommand;
 use init::InitCommand;
 use log::LevelFilter;
+use polyfill::PolyfillCommand;
 use transpile::TranspileCommand;
+use vendor::VendorCommand;
 
 pub const DEFAULT_MANIFEST_PATH: &str = "dalbit.toml";
 
@@ -22,6 +26,8 @@ pub enum CliSubcommand {
     Init(InitCommand),
     Fetch(FetchCommand),
     Clean(CleanCommand),
+    Polyfill(PolyfillCommand),
+    Vendor(VendorCommand),
 }
 
 #[derive(Debug, Args, Clone)]
@@ -29,6 +35,9 @@ pub struct GlobalOptions {
     /// Sets verbosity level (can be specified multiple times)
     #[arg(long, short, global(true), action = clap::ArgAction::Count)]
     verbose: u8,
+    /// Sets cache directory of polyfills (overrides `DALBIT_CACHE_DIR` and the manifest)
+    #[arg(long, global(true))]
+    cache_dir: Option<PathBuf>,
 }
 
 impl GlobalOptions {
@@ -54,11 +63,15 @@ pub struct Dalbit {
 
 impl Dalbit {
     pub async fn run(self) -> Result<ExitCode> {
+        kaledis_dalbit::polyfill::set_cache_dir(self.global_options.cache_dir.clone());
+
         match self.subcommand {
             CliSubcommand::Transpile(cmd) => cmd.run().await,
             CliSubcommand::Init(cmd) => cmd.run().await,
             CliSubcommand::Fetch(cmd) => cmd.run().await,
             CliSubcommand::Clean(cmd) => cmd.run().await,
+            CliSubcommand::Polyfill(cmd) => cmd.run().await,
+            CliSubcommand::Vendor(cmd) => cmd.run().await,
         }
     }
 
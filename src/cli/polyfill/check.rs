@@ -0,0 +1,35 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use anyhow::Result;
+use clap::Parser;
+use kaledis_dalbit::authoring;
+
+/// Validate a polyfill repository
+#[derive(Debug, Clone, Parser)]
+pub struct CheckCommand {
+    /// Directory of the polyfill
+    #[arg(default_value = ".")]
+    dir: PathBuf,
+}
+
+impl CheckCommand {
+    pub async fn run(self) -> Result<ExitCode> {
+        let problems = authoring::check(&self.dir).await?;
+
+        if problems.is_empty() {
+            println!("Polyfill is valid");
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        for problem in &problems {
+            eprintln!("-> {}", problem);
+        }
+        eprintln!(
+            "{} problem{} found",
+            problems.len(),
+            if problems.len() > 1 { "s" } else { "" }
+        );
+
+        Ok(ExitCode::FAILURE)
+    }
+}
@@ -0,0 +1,77 @@
+use std::{collections::HashSet, process::ExitCode};
+
+use anyhow::Result;
+use clap::Parser;
+use kaledis_dalbit::{injector, manifest::Manifest, utils, TargetVersion};
+
+use crate::cli::DEFAULT_MANIFEST_PATH;
+
+/// Print the configured polyfills and their exports
+#[derive(Debug, Clone, Parser)]
+pub struct InfoCommand {}
+
+impl InfoCommand {
+    pub async fn run(self) -> Result<ExitCode> {
+        let manifest = Manifest::from_file(DEFAULT_MANIFEST_PATH).await?;
+        let polyfill = manifest.polyfill();
+        let polyfill_layers = polyfill.caches(manifest.target_version()).await?;
+
+        // exports referenced by the project's sources, before transpiling
+        let mut used_exports = HashSet::new();
+        let exports = polyfill_layers.globals_exports();
+        for path in utils::collect_lua_files(manifest.input()).await? {
+            let ast = utils::parse_file(&path, &TargetVersion::Luau).await?;
+            used_exports.extend(injector::collect_used_exports(&ast, &exports));
+        }
+
+        for (source, cache) in polyfill.sources().iter().zip(polyfill_layers.caches()) {
+            println!("{}", source);
+            if let Some(version) = cache.version() {
+                println!("  version: {}", version);
+            }
+            if let Some(commit_id) = cache.commit_id()? {
+                println!("  commit: {}", commit_id);
+            }
+            println!("  globals: {}", cache.globals_path().display());
+
+            println!("  exports:");
+            let mut exports: Vec<&String> = cache.globals_exports().iter().collect();
+            exports.sort();
+            for export in exports {
+                let mut notes = Vec::new();
+                if used_exports.contains(export) {
+                    notes.push("used");
+                }
+                if cache.extends().iter().flatten().any(|lib| lib == export) {
+                    notes.push("extends builtin");
+                }
+                if polyfill.globals().get(export) == Some(&false) {
+                    notes.push("disabled");
+                }
+                if notes.is_empty() {
+                    println!("    {}", export);
+                } else {
+                    println!("    {} ({})", export, notes.join(", "));
+                }
+            }
+
+            println!("  config:");
+            let mut config: Vec<_> = cache.config().iter().collect();
+            config.sort_by_key(|(key, _)| *key);
+            for (key, default) in config {
+                if let Some(value) = polyfill.config().get(key) {
+                    println!("    {} = {} (project: {})", key, default, value);
+                } else {
+                    println!("    {} = {}", key, default);
+                }
+            }
+
+            println!("  removes:");
+            for name in cache.removes().iter().flatten() {
+                println!("    {}", name);
+            }
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
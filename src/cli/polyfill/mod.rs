@@ -0,0 +1,36 @@
+use std::process::ExitCode;
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+
+mod check;
+mod info;
+mod new;
+
+use check::CheckCommand;
+use info::InfoCommand;
+use new::NewCommand;
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum PolyfillSubcommand {
+    Info(InfoCommand),
+    New(NewCommand),
+    Check(CheckCommand),
+}
+
+/// Inspect and author dalbit polyfills
+#[derive(Debug, Clone, Parser)]
+pub struct PolyfillCommand {
+    #[clap(subcommand)]
+    subcommand: PolyfillSubcommand,
+}
+
+impl PolyfillCommand {
+    pub async fn run(self) -> Result<ExitCode> {
+        match self.subcommand {
+            PolyfillSubcommand::Info(cmd) => cmd.run().await,
+            PolyfillSubcommand::New(cmd) => cmd.run().await,
+            PolyfillSubcommand::Check(cmd) => cmd.run().await,
+        }
+    }
+}
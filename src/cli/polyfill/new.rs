@@ -0,0 +1,22 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use anyhow::Result;
+use clap::Parser;
+use kaledis_dalbit::authoring;
+
+/// Create a new polyfill repository
+#[derive(Debug, Clone, Parser)]
+pub struct NewCommand {
+    /// Directory of the new polyfill
+    dir: PathBuf,
+}
+
+impl NewCommand {
+    pub async fn run(self) -> Result<ExitCode> {
+        authoring::scaffold(&self.dir).await?;
+
+        println!("Created polyfill in {}", self.dir.display());
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
@@ -23,6 +23,6 @@ impl TranspileCommand {
 
         println!("Successfully transpiled in {}", process_duration);
 
-        return Ok(ExitCode::SUCCESS);
+        Ok(ExitCode::SUCCESS)
     }
 }
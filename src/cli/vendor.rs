@@ -0,0 +1,35 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use anyhow::Result;
+use clap::Parser;
+use kaledis_dalbit::manifest::Manifest;
+
+use super::DEFAULT_MANIFEST_PATH;
+
+const DEFAULT_VENDOR_PATH: &str = "polyfills";
+
+/// Copy dalbit polyfills into the project and use the copies
+#[derive(Debug, Clone, Parser)]
+pub struct VendorCommand {
+    /// Directory to copy polyfills into
+    #[arg(default_value = DEFAULT_VENDOR_PATH)]
+    path: PathBuf,
+}
+
+impl VendorCommand {
+    pub async fn run(self) -> Result<ExitCode> {
+        let mut manifest = Manifest::from_file(DEFAULT_MANIFEST_PATH).await?;
+        let target_version = manifest.target_version().to_owned();
+        let vendored = manifest
+            .polyfill
+            .vendor(&self.path, &target_version)
+            .await?;
+        manifest.write_polyfill_sources(DEFAULT_MANIFEST_PATH).await?;
+
+        for path in vendored {
+            println!("Vendored polyfill into {}", path.display());
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
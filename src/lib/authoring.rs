@@ -0,0 +1,276 @@
+//! Tools for writing polyfills.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{anyhow, Result};
+use full_moon::{
+    tokenizer::{Token, TokenType},
+    visitors::Visitor,
+};
+use git2::Repository;
+use tokio::fs;
+
+use crate::{
+    manifest::Manifest,
+    modifiers::ModifierRegistry,
+    polyfill::PolyfillManifest,
+    transpile::{self, DALBIT_GLOBAL_IDENTIFIER_PREFIX},
+    utils, TargetVersion,
+};
+
+pub const POLYFILL_MANIFEST_NAME: &str = "polyfill.toml";
+
+const TEMPLATE_GLOBALS: &str = r#"-- Globals of this polyfill. Every field of the returned table can be injected into
+-- transpiled files. Config values are available as `DALBIT_<key>` globals.
+
+local function example(): string
+	if DALBIT_example_flag then
+		return "enabled"
+	end
+	return "disabled"
+end
+
+return {
+	example = example,
+}
+"#;
+
+/// Lua targets checked when a polyfill doesn't declare its targets.
+const DEFAULT_CHECK_TARGETS: [TargetVersion; 3] = [
+    TargetVersion::Lua51,
+    TargetVersion::Lua52,
+    TargetVersion::Lua53,
+];
+
+#[inline]
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Collects config globals (`DALBIT_<key>`) referenced in the AST.
+#[derive(Default)]
+struct CollectConfigGlobals {
+    keys: HashSet<String>,
+}
+
+impl Visitor for CollectConfigGlobals {
+    fn visit_identifier(&mut self, identifier: &Token) {
+        if let TokenType::Identifier { identifier } = identifier.token_type() {
+            if let Some(key) = identifier.strip_prefix(DALBIT_GLOBAL_IDENTIFIER_PREFIX) {
+                self.keys.insert(key.to_owned());
+            }
+        }
+    }
+}
+
+/// Creates a new polyfill repository with a manifest, a globals module and a sample config.
+pub async fn scaffold(dir: &Path) -> Result<()> {
+    if dir.exists() && dir.read_dir()?.next().is_some() {
+        return Err(anyhow!("Directory {:?} is not empty", dir));
+    }
+    fs::create_dir_all(dir).await?;
+
+    let manifest = PolyfillManifest::default();
+    manifest.write(dir.join(POLYFILL_MANIFEST_NAME)).await?;
+    fs::write(dir.join(manifest.globals()), TEMPLATE_GLOBALS).await?;
+    Repository::init(dir)?;
+
+    Ok(())
+}
+
+/// Checks a polyfill repository and returns every problem found.
+pub async fn check(dir: &Path) -> Result<Vec<String>> {
+    let manifest = PolyfillManifest::from_file(dir.join(POLYFILL_MANIFEST_NAME)).await?;
+    let mut problems = Vec::new();
+
+    let targets = manifest
+        .targets()
+        .clone()
+        .unwrap_or_else(|| DEFAULT_CHECK_TARGETS.to_vec());
+    for target in manifest.target_globals().keys() {
+        if !targets.contains(target) {
+            problems.push(format!(
+                "Globals are declared for unsupported target `{}`",
+                target
+            ));
+        }
+    }
+
+    let mut globals_paths: Vec<(&PathBuf, &TargetVersion)> = Vec::new();
+    for target in &targets {
+        let globals = (
+            manifest.globals_for(target),
+            manifest.lua_version_for(target),
+        );
+        if !globals_paths.contains(&globals) {
+            globals_paths.push(globals);
+        }
+    }
+
+    let mut collect_config_globals = CollectConfigGlobals::default();
+    let mut parsed = true;
+    for (globals_path, lua_version) in globals_paths {
+        let globals_path = dir.join(globals_path);
+        let ast = match utils::parse_file(&globals_path, lua_version).await {
+            Ok(ast) => ast,
+            Err(err) => {
+                problems.push(format!(
+                    "Could not parse globals {:?} as `{}`: {}",
+                    globals_path, lua_version, err
+                ));
+                parsed = false;
+                continue;
+            }
+        };
+
+        let exports = utils::get_exports_from_ast(&ast).unwrap_or_default();
+        if exports.is_empty() {
+            problems.push(format!(
+                "Globals {:?} must return at least one global in a table constructor or a module table",
+                globals_path
+            ));
+        }
+        for export in &exports {
+            if !is_identifier(export) {
+                problems.push(format!("Export `{}` is not a valid identifier", export));
+            }
+        }
+        check_exports(&manifest, &globals_path, &exports, &mut problems);
+        collect_config_globals.visit_ast(&ast);
+    }
+    check_removes(&manifest, &mut problems);
+    if !parsed {
+        return Ok(problems);
+    }
+
+    check_config(&manifest, &collect_config_globals.keys, &mut problems);
+
+    for target in targets {
+        let globals_path = dir.join(manifest.globals_for(&target));
+        let untranspiled_lua_version = manifest.untranspiled_lua_version_for(&target);
+        if let Err(err) =
+            check_target(&manifest, &globals_path, untranspiled_lua_version, &target).await
+        {
+            problems.push(format!("Could not transpile for `{}`: {:#}", target, err));
+        }
+    }
+
+    Ok(problems)
+}
+
+fn check_removes(manifest: &PolyfillManifest, problems: &mut Vec<String>) {
+    let mut seen = HashSet::new();
+    for name in manifest.removes().iter().flatten() {
+        if !is_identifier(name) {
+            problems.push(format!(
+                "Removed global `{}` is not a valid identifier",
+                name
+            ));
+        }
+        if !seen.insert(name) {
+            problems.push(format!(
+                "Removed global `{}` is listed more than once",
+                name
+            ));
+        }
+    }
+}
+
+/// Checks removed and extended globals against the exports of a globals module.
+fn check_exports(
+    manifest: &PolyfillManifest,
+    globals_path: &Path,
+    exports: &HashSet<String>,
+    problems: &mut Vec<String>,
+) {
+    for name in manifest.removes().iter().flatten() {
+        if exports.contains(name) {
+            problems.push(format!(
+                "Removed global `{}` is also exported by {:?}",
+                name, globals_path
+            ));
+        }
+    }
+    for name in manifest.extends().iter().flatten() {
+        if !exports.contains(name) {
+            problems.push(format!(
+                "Extended library `{}` is not exported by {:?}",
+                name, globals_path
+            ));
+        }
+    }
+}
+
+fn check_config(
+    manifest: &PolyfillManifest,
+    used_keys: &HashSet<String>,
+    problems: &mut Vec<String>,
+) {
+    let mut keys: Vec<&String> = manifest.config().keys().collect();
+    keys.sort();
+    for key in keys {
+        if !is_identifier(key) {
+            problems.push(format!("Config key `{}` is not a valid identifier", key));
+        } else if !used_keys.contains(key) {
+            problems.push(format!(
+                "Config key `{}` is never used as `{}{}`",
+                key, DALBIT_GLOBAL_IDENTIFIER_PREFIX, key
+            ));
+        }
+    }
+
+    let mut used_keys: Vec<&String> = used_keys.iter().collect();
+    used_keys.sort();
+    for key in used_keys {
+        if !manifest.config().contains_key(key) {
+            problems.push(format!(
+                "`{}{}` is used but `{}` is not declared in config",
+                DALBIT_GLOBAL_IDENTIFIER_PREFIX, key, key
+            ));
+        }
+    }
+}
+
+/// Transpiles globals for the target like a project would, then parses the result.
+async fn check_target(
+    polyfill_manifest: &PolyfillManifest,
+    globals_path: &PathBuf,
+    untranspiled_lua_version: Option<&TargetVersion>,
+    target: &TargetVersion,
+) -> Result<()> {
+    let manifest = Manifest {
+        target_version: target.clone(),
+        minify: false,
+        file_extension: None,
+        ..Default::default()
+    };
+    let output = std::env::temp_dir()
+        .join(format!("dalbit-check-{}", process::id()))
+        .join(format!("{}.lua", target));
+
+    let result = async {
+        transpile::transpile_polyfill_globals(
+            &manifest,
+            &ModifierRegistry::default(),
+            globals_path,
+            untranspiled_lua_version,
+            polyfill_manifest.config(),
+            &output,
+        )
+        .await?;
+        utils::parse_file(&output, target).await
+    }
+    .await;
+
+    if let Some(parent) = output.parent() {
+        let _ = fs::remove_dir_all(parent).await;
+    }
+
+    result.map(|_| ())
+}
@@ -0,0 +1,177 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use tokio::fs;
+
+use crate::{manifest::Manifest, modifiers::ModifierRegistry, TargetVersion};
+
+/// Prefix of directive comments.
+pub const DIRECTIVE_PREFIX: &str = "--!dalbit";
+
+/// Per-file overrides declared by `--!dalbit` comments at the top of a file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileDirectives {
+    /// `no-polyfill`: the file isn't injected with polyfill globals.
+    no_polyfill: bool,
+    /// `disable=a,b`: modifiers disabled for the file.
+    disabled_modifiers: Vec<String>,
+    /// `target=lua51`: target version of the file.
+    target_version: Option<TargetVersion>,
+}
+
+/// Gets the length of a long bracket opening the text, like `[==[`, and its closing bracket.
+fn long_bracket(text: &str) -> Option<(usize, String)> {
+    let rest = text.strip_prefix('[')?;
+    let level = rest.len() - rest.trim_start_matches('=').len();
+    rest[level..]
+        .starts_with('[')
+        .then(|| (level + 2, format!("]{}]", "=".repeat(level))))
+}
+
+impl FileDirectives {
+    /// Parses directives from the leading comments of the code.
+    pub fn parse(code: &str) -> Result<Self> {
+        let mut directives = Self::default();
+        // closing bracket of the multi-line comment the current line is in
+        let mut comment_end: Option<String> = None;
+        for line in code.lines() {
+            let mut line = line.trim();
+            if let Some(end) = &comment_end {
+                let Some(index) = line.find(end.as_str()) else {
+                    continue;
+                };
+                line = line[index + end.len()..].trim();
+                comment_end = None;
+            }
+            if line.is_empty() || line.starts_with("#!") {
+                continue;
+            }
+            if !line.starts_with("--") {
+                break;
+            }
+            if let Some((start, end)) = long_bracket(&line[2..]) {
+                let comment = &line[2 + start..];
+                match comment.find(&end) {
+                    Some(index) if comment[index + end.len()..].trim().is_empty() => {}
+                    // code follows the comment on the same line
+                    Some(_) => break,
+                    None => comment_end = Some(end),
+                }
+                continue;
+            }
+            let Some(options) = line.strip_prefix(DIRECTIVE_PREFIX) else {
+                continue;
+            };
+            for option in options.split_whitespace() {
+                match option.split_once('=') {
+                    None if option == "no-polyfill" => directives.no_polyfill = true,
+                    Some(("disable", modifiers)) => directives.disabled_modifiers.extend(
+                        modifiers
+                            .split(',')
+                            .filter(|name| !name.is_empty())
+                            .map(str::to_owned),
+                    ),
+                    Some(("target", target_version)) => {
+                        directives.target_version = Some(
+                            toml::Value::String(target_version.to_owned())
+                                .try_into()
+                                .map_err(|_| anyhow!("Invalid target `{}`", target_version))?,
+                        )
+                    }
+                    _ => return Err(anyhow!("Unknown directive `{}`", option)),
+                }
+            }
+        }
+        Ok(directives)
+    }
+
+    /// Reads directives of a file.
+    pub async fn from_file(path: &Path) -> Result<Self> {
+        let code = fs::read_to_string(path).await?;
+        Self::parse(&code).with_context(|| format!("Invalid dalbit directive in {:?}", path))
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether the file has to be transpiled with its own manifest.
+    #[inline]
+    pub fn overrides_manifest(&self) -> bool {
+        !self.disabled_modifiers.is_empty() || self.target_version.is_some()
+    }
+
+    /// Creates the manifest the file is transpiled with.
+    pub fn apply(&self, manifest: &Manifest, registry: &ModifierRegistry) -> Result<Manifest> {
+        let mut manifest = manifest.clone();
+        for name in &self.disabled_modifiers {
+            if !registry.contains(name) {
+                return Err(anyhow!("Unknown modifier `{}` in dalbit directive", name));
+            }
+            manifest.modifiers.insert(name.to_owned(), false.into());
+        }
+        if let Some(target_version) = &self.target_version {
+            manifest.target_version = target_version.clone();
+        }
+        Ok(manifest)
+    }
+
+    #[inline]
+    pub fn no_polyfill(&self) -> bool {
+        self.no_polyfill
+    }
+
+    #[inline]
+    pub fn disabled_modifiers(&self) -> &Vec<String> {
+        &self.disabled_modifiers
+    }
+
+    #[inline]
+    pub fn target_version(&self) -> &Option<TargetVersion> {
+        &self.target_version
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_directives() {
+        let directives = FileDirectives::parse(
+            "#!/usr/bin/lua\n-- comment\n--!dalbit no-polyfill disable=a,b\n--!dalbit target=lua51 disable=c\nlocal x",
+        )
+        .unwrap();
+        assert!(directives.no_polyfill());
+        assert_eq!(directives.disabled_modifiers(), &["a", "b", "c"]);
+        assert_eq!(directives.target_version(), &Some(TargetVersion::Lua51));
+    }
+
+    #[test]
+    fn stops_at_code() {
+        let directives = FileDirectives::parse("local x\n--!dalbit no-polyfill").unwrap();
+        assert!(directives.is_empty());
+        let directives = FileDirectives::parse("--[[ a ]] local x\n--!dalbit no-polyfill").unwrap();
+        assert!(directives.is_empty());
+    }
+
+    #[test]
+    fn skips_multi_line_comments() {
+        let directives = FileDirectives::parse(
+            "--[[\n  License\n  --!dalbit disable=a\n]]\n--[==[ b ]] ]==]\n--!dalbit no-polyfill",
+        )
+        .unwrap();
+        assert!(directives.no_polyfill());
+        assert!(directives.disabled_modifiers().is_empty());
+
+        let directives = FileDirectives::parse("--[[\n]] --!dalbit no-polyfill").unwrap();
+        assert!(directives.no_polyfill());
+    }
+
+    #[test]
+    fn rejects_invalid_directives() {
+        assert!(FileDirectives::parse("--!dalbit target=lua99").is_err());
+        assert!(FileDirectives::parse("--!dalbit unknown").is_err());
+    }
+}
@@ -1,21 +1,31 @@
 use std::{
     borrow::Cow,
-    collections::HashSet,
-    path::{Path, PathBuf},
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::{self, Path, PathBuf},
 };
 
 use anyhow::{anyhow, Result};
 use full_moon::{
-    tokenizer::{Token, TokenType},
+    ast::{
+        Ast, Block, Expression, Field, FunctionBody, FunctionDeclaration, GenericFor, LastStmt,
+        LocalAssignment, LocalFunction, NumericFor, Parameter, Prefix, Repeat, Stmt, Var,
+    },
+    node::Node,
+    tokenizer::{Lexer, LexerResult, StringLiteralQuoteType, TokenReference, TokenType},
     visitors::Visitor,
     LuaVersion,
 };
-use path_slash::PathBufExt;
+use path_slash::PathExt;
 use pathdiff::diff_paths;
 use tokio::fs;
 
+use crate::{
+    directives::FileDirectives, polyfill::RequireStyle, sandbox::Sandbox, utils, TargetVersion,
+};
+
 #[inline]
-fn make_relative(path: &PathBuf) -> Cow<Path> {
+fn make_relative(path: &PathBuf) -> Cow<'_, Path> {
     if path.starts_with(".") | path.starts_with("..") {
         Cow::Borrowed(path.as_path())
     } else {
@@ -23,10 +33,27 @@ fn make_relative(path: &PathBuf) -> Cow<Path> {
     }
 }
 
+/// Collects libraries referenced as free global variables, resolving local scopes.
 #[derive(Debug)]
 struct CollectUsedLibraries {
     libraries: HashSet<String>,
     used_libraries: HashSet<String>,
+    /// Byte ranges of the references to libraries.
+    references: Vec<(String, Range<usize>)>,
+    /// Locals of each open block, with the depth of the function the block is in.
+    scopes: Vec<(HashSet<String>, usize)>,
+    /// Locals declared by the next block's header, such as parameters and loop variables.
+    pending_locals: Vec<String>,
+    /// Pending locals of enclosing headers while a function body in them is visited.
+    saved_pending_locals: Vec<Vec<String>>,
+    /// Number of open scopes with each open `repeat` block, whose scope covers its `until`.
+    repeat_scopes: Vec<usize>,
+    /// Whether the next function body is a method's, which has an implicit `self`.
+    pending_self: bool,
+    /// Upvalues of each open function. Libraries count as locals of the main chunk.
+    function_upvalues: Vec<HashSet<String>>,
+    max_upvalues: usize,
+    max_main_locals: usize,
 }
 
 impl CollectUsedLibraries {
@@ -34,19 +61,452 @@ impl CollectUsedLibraries {
         Self {
             libraries,
             used_libraries: HashSet::new(),
+            references: Vec::new(),
+            scopes: Vec::new(),
+            pending_locals: Vec::new(),
+            saved_pending_locals: Vec::new(),
+            repeat_scopes: Vec::new(),
+            pending_self: false,
+            function_upvalues: Vec::new(),
+            max_upvalues: 0,
+            max_main_locals: 0,
+        }
+    }
+
+    #[inline]
+    fn function_depth(&self) -> usize {
+        self.function_upvalues.len()
+    }
+
+    /// Updates the maximum number of active locals of the main chunk.
+    fn count_main_locals(&mut self) {
+        if self.function_depth() == 0 {
+            let main_locals = self
+                .scopes
+                .iter()
+                .filter(|(_, depth)| *depth == 0)
+                .map(|(scope, _)| scope.len())
+                .sum();
+            self.max_main_locals = self.max_main_locals.max(main_locals);
+        }
+    }
+
+    fn declare_local(&mut self, token: &TokenReference) {
+        if let (Some((scope, _)), TokenType::Identifier { identifier }) =
+            (self.scopes.last_mut(), token.token_type())
+        {
+            scope.insert(identifier.to_string());
+            self.count_main_locals();
+        }
+    }
+
+    fn reference(&mut self, token: &TokenReference) {
+        let TokenType::Identifier { identifier } = token.token_type() else {
+            return;
+        };
+        let identifier = identifier.as_str();
+        let local_depth = self
+            .scopes
+            .iter()
+            .rev()
+            .find(|(scope, _)| scope.contains(identifier))
+            .map(|(_, depth)| *depth);
+
+        let declared_depth = match local_depth {
+            Some(depth) => depth,
+            None if self.libraries.contains(identifier) => {
+                self.used_libraries.insert(identifier.to_owned());
+                self.references.push((
+                    identifier.to_owned(),
+                    token.token().start_position().bytes()..token.token().end_position().bytes(),
+                ));
+                0
+            }
+            None => return,
+        };
+        for upvalues in self.function_upvalues.iter_mut().skip(declared_depth) {
+            upvalues.insert(identifier.to_owned());
         }
     }
 }
 
 impl Visitor for CollectUsedLibraries {
-    fn visit_identifier(&mut self, identifier: &Token) {
-        if let TokenType::Identifier { identifier } = identifier.token_type() {
-            let identifier = identifier.to_string();
-            if self.libraries.contains(&identifier) {
-                self.used_libraries.insert(identifier);
+    fn visit_block(&mut self, _node: &Block) {
+        let scope = self.pending_locals.drain(..).collect::<HashSet<_>>();
+        self.scopes.push((scope, self.function_depth()));
+        self.count_main_locals();
+    }
+
+    fn visit_block_end(&mut self, _node: &Block) {
+        // the scope of a `repeat` block is closed after its `until` condition
+        if self.repeat_scopes.last() != Some(&self.scopes.len()) {
+            self.scopes.pop();
+        }
+    }
+
+    fn visit_repeat(&mut self, _node: &Repeat) {
+        self.repeat_scopes.push(self.scopes.len() + 1);
+    }
+
+    fn visit_repeat_end(&mut self, _node: &Repeat) {
+        self.repeat_scopes.pop();
+        self.scopes.pop();
+    }
+
+    fn visit_function_body(&mut self, node: &FunctionBody) {
+        let mut parameters: Vec<String> = node
+            .parameters()
+            .iter()
+            .filter_map(|parameter| match parameter {
+                Parameter::Name(name) => Some(name.token().to_string()),
+                _ => None,
+            })
+            .collect();
+        if std::mem::take(&mut self.pending_self) {
+            parameters.push("self".to_owned());
+        }
+        self.saved_pending_locals
+            .push(std::mem::replace(&mut self.pending_locals, parameters));
+        self.function_upvalues.push(HashSet::new());
+    }
+
+    fn visit_function_body_end(&mut self, _node: &FunctionBody) {
+        self.pending_locals = self.saved_pending_locals.pop().unwrap_or_default();
+        if let Some(upvalues) = self.function_upvalues.pop() {
+            self.max_upvalues = self.max_upvalues.max(upvalues.len());
+        }
+    }
+
+    fn visit_function_declaration(&mut self, node: &FunctionDeclaration) {
+        let name = node.name();
+        if let Some(first) = name.names().iter().next() {
+            self.reference(first);
+        }
+        self.pending_self = name.method_name().is_some();
+    }
+
+    fn visit_local_function(&mut self, node: &LocalFunction) {
+        self.declare_local(node.name());
+    }
+
+    fn visit_local_assignment_end(&mut self, node: &LocalAssignment) {
+        for name in node.names() {
+            self.declare_local(name);
+        }
+    }
+
+    fn visit_numeric_for(&mut self, node: &NumericFor) {
+        self.pending_locals
+            .push(node.index_variable().token().to_string());
+        self.pending_locals
+            .extend(FOR_HIDDEN_LOCALS.map(String::from));
+    }
+
+    fn visit_generic_for(&mut self, node: &GenericFor) {
+        for name in node.names() {
+            self.pending_locals.push(name.token().to_string());
+        }
+        self.pending_locals
+            .extend(FOR_HIDDEN_LOCALS.map(String::from));
+    }
+
+    fn visit_var(&mut self, node: &Var) {
+        if let Var::Name(name) = node {
+            self.reference(name);
+        }
+    }
+
+    fn visit_prefix(&mut self, node: &Prefix) {
+        if let Prefix::Name(name) = node {
+            self.reference(name);
+        }
+    }
+}
+
+/// Locals a `for` loop declares for its internal state, named so they can't be referenced.
+const FOR_HIDDEN_LOCALS: [&str; 3] = ["(for state 1)", "(for state 2)", "(for state 3)"];
+
+/// Maximum number of active locals in a function.
+const MAX_LOCALS: usize = 200;
+
+/// Gets the maximum number of upvalues in a function.
+fn max_upvalues(lua_version: LuaVersion) -> usize {
+    if lua_version.has_luau() {
+        200
+    } else if lua_version.has_lua52() {
+        255
+    } else {
+        60
+    }
+}
+
+/// Finds the byte offset to inject at, which is after a leading shebang and comments.
+///
+/// The offset is at the start of the line following them, or right after them when no line break
+/// follows. The header is placed there without adding a line.
+fn header_offset(ast: &Ast) -> usize {
+    let first_token = ast.nodes().tokens().next().unwrap_or_else(|| ast.eof());
+
+    let mut offset = 0;
+    let mut after_comment = false;
+    for trivia in first_token.leading_trivia() {
+        match trivia.token_type() {
+            TokenType::Shebang { .. }
+            | TokenType::SingleLineComment { .. }
+            | TokenType::MultiLineComment { .. } => {
+                offset = trivia.end_position().bytes();
+                after_comment = true;
+            }
+            TokenType::Whitespace { characters } if after_comment => {
+                if let Some(index) = characters.find('\n') {
+                    offset = trivia.start_position().bytes() + index + 1;
+                }
+                after_comment = false;
+            }
+            _ => {}
+        }
+    }
+    offset
+}
+
+/// Unescapes line breaks and `\z` skips of a quoted string literal.
+fn unescape_line_breaks(literal: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = literal.chars().peekable();
+    while let Some(char) = chars.next() {
+        if char != '\\' {
+            unescaped.push(char);
+            continue;
+        }
+        match chars.next() {
+            Some(line_break @ ('\n' | '\r')) => {
+                if chars
+                    .peek()
+                    .is_some_and(|next| matches!(next, '\n' | '\r') && *next != line_break)
+                {
+                    chars.next();
+                }
+                unescaped.push_str("\\n");
+            }
+            Some('z') => {
+                while chars.peek().is_some_and(|next| next.is_ascii_whitespace()) {
+                    chars.next();
+                }
+            }
+            Some(escaped) => {
+                unescaped.push('\\');
+                unescaped.push(escaped);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Quotes the content of a long bracket string literal.
+fn quote_long_string(literal: &str) -> String {
+    // lua reads any line break of a long string as `\n`, except one right after the opening
+    // bracket which isn't part of the string
+    let literal = literal.replace("\r\n", "\n").replace('\r', "\n");
+    let literal = literal.strip_prefix('\n').unwrap_or(&literal);
+    let mut quoted = String::from('"');
+    for char in literal.chars() {
+        match char {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Rewrites code on a single line, dropping comments and escaping line breaks in strings.
+pub fn single_line(code: &str, lua_version: LuaVersion) -> Result<String> {
+    let tokens = match Lexer::new(code, lua_version).collect() {
+        LexerResult::Ok(tokens) => tokens,
+        result => return Err(anyhow!("{:?}", result.errors())),
+    };
+
+    let mut line = String::new();
+    for token in tokens {
+        match token.token_type() {
+            TokenType::Shebang { .. } | TokenType::Eof => {}
+            TokenType::SingleLineComment { .. } | TokenType::MultiLineComment { .. } => {
+                line.push(' ')
+            }
+            TokenType::Whitespace { characters } if characters.contains(['\n', '\r']) => {
+                line.push(' ')
             }
+            TokenType::StringLiteral {
+                literal,
+                quote_type,
+                ..
+            } if literal.contains(['\n', '\r']) => match quote_type {
+                StringLiteralQuoteType::Brackets => line.push_str(&quote_long_string(literal)),
+                _ => line.push_str(&format!(
+                    "{}{}{}",
+                    quote_type,
+                    unescape_line_breaks(literal),
+                    quote_type
+                )),
+            },
+            _ => line.push_str(&token.to_string()),
         }
     }
+    Ok(line)
+}
+
+/// Whether evaluating the expression has no side effects.
+fn is_pure(expression: &Expression) -> bool {
+    match expression {
+        Expression::Function(_)
+        | Expression::Number(_)
+        | Expression::String(_)
+        | Expression::Symbol(_)
+        | Expression::Var(Var::Name(_)) => true,
+        Expression::Parentheses { expression, .. } => is_pure(expression),
+        Expression::TableConstructor(table) => table.fields().iter().all(|field| match field {
+            Field::ExpressionKey { key, value, .. } => is_pure(key) && is_pure(value),
+            Field::NameKey { value, .. } | Field::NoKey(value) => is_pure(value),
+            _ => false,
+        }),
+        _ => false,
+    }
+}
+
+/// Collects names of the identifiers in the given node.
+fn identifiers(node: &impl Node) -> HashSet<String> {
+    node.tokens()
+        .filter_map(|token| match token.token_type() {
+            TokenType::Identifier { identifier } => Some(identifier.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Keeps only the given exports of a module that returns a table constructor, along with the
+/// statements they need. Other modules are returned as they are.
+///
+/// Top-level locals that aren't referenced anymore are dropped when their values have no side
+/// effects, so every other statement still runs.
+fn shake_module(
+    source: &str,
+    exports: &HashSet<String>,
+    lua_version: LuaVersion,
+) -> Result<String> {
+    let ast = utils::parse_code(source, lua_version)?;
+    let block = ast.nodes();
+    let Some(LastStmt::Return(return_stmt)) = block.last_stmt() else {
+        return Ok(source.to_owned());
+    };
+    let [Expression::TableConstructor(table)] =
+        return_stmt.returns().iter().collect::<Vec<_>>().as_slice()
+    else {
+        return Ok(source.to_owned());
+    };
+
+    let mut fields = Vec::new();
+    let mut referenced = HashSet::new();
+    for field in table.fields() {
+        let Field::NameKey { key, value, .. } = field else {
+            return Ok(source.to_owned());
+        };
+        if exports.contains(&key.token().to_string()) {
+            // a line break ends a trailing comment before the next separator
+            fields.push(format!("{}={}\n", key.token(), value));
+            referenced.extend(identifiers(value));
+        }
+    }
+
+    // (code, names declared by a statement that can be dropped, identifiers in the statement)
+    let mut statements: Vec<(String, Vec<String>, HashSet<String>)> = block
+        .stmts_with_semicolon()
+        .map(|(stmt, semicolon)| {
+            let declared = match stmt {
+                Stmt::LocalFunction(local_function) => {
+                    vec![local_function.name().token().to_string()]
+                }
+                Stmt::LocalAssignment(local_assignment)
+                    if local_assignment.expressions().iter().all(is_pure) =>
+                {
+                    local_assignment
+                        .names()
+                        .iter()
+                        .map(|name| name.token().to_string())
+                        .collect()
+                }
+                _ => Vec::new(),
+            };
+            let code = match semicolon {
+                Some(semicolon) => format!("{}{}\n", stmt, semicolon),
+                None => format!("{}\n", stmt),
+            };
+            (code, declared, identifiers(stmt))
+        })
+        .collect();
+
+    while let Some(index) = (0..statements.len()).find(|&index| {
+        let declared = &statements[index].1;
+        !declared.is_empty()
+            && declared.iter().all(|name| {
+                !referenced.contains(name)
+                    && statements
+                        .iter()
+                        .enumerate()
+                        .all(|(other, (_, _, identifiers))| {
+                            other == index || !identifiers.contains(name)
+                        })
+            })
+    }) {
+        statements.remove(index);
+    }
+
+    let mut module: String = statements.into_iter().map(|(code, ..)| code).collect();
+    module.push_str("return {");
+    module.push_str(&fields.join(","));
+    module.push('}');
+    Ok(module)
+}
+
+const INLINE_MODULE_IDENTIFIER: &str = "_DALBIT_POLYFILL";
+
+/// Table of removed globals, used instead of locals when there would be too many locals.
+const REMOVED_IDENTIFIER: &str = "_DALBIT_REMOVED";
+
+/// Comments enclosing the injected header, so it can be found and replaced later.
+const HEADER_START: &str = "--[[dalbit:polyfill]]";
+const HEADER_END: &str = "--[[/dalbit:polyfill]]";
+
+/// Removes a header injected by a previous run, if any.
+fn remove_header(code: &mut String) {
+    if let Some(start) = code.find(HEADER_START) {
+        if let Some(end) = code[start..].find(HEADER_END) {
+            code.replace_range(start..start + end + HEADER_END.len(), "");
+        }
+    }
+}
+
+/// Inserts the header at the injection offset without adding a line, so line numbers of the
+/// rest of the file are kept.
+fn insert_header(code: &mut String, offset: usize, libraries_texts: &[String]) {
+    let mut header = String::new();
+    // a comment ending the file without a line break would comment out the header
+    if offset > 0 && offset == code.len() && !code.ends_with('\n') {
+        header.push('\n');
+    }
+    header.push_str(HEADER_START);
+    header.push_str(&libraries_texts.join(""));
+    header.push_str(HEADER_END);
+    code.insert_str(offset, &header);
+}
+
+/// Collects exports that are referenced as global variables in the given AST.
+pub fn collect_used_exports(ast: &Ast, exports: &HashSet<String>) -> HashSet<String> {
+    let mut collect_used_libs = CollectUsedLibraries::new(exports.clone());
+    collect_used_libs.visit_ast(ast);
+    collect_used_libs.used_libraries
 }
 
 /// Injector that injects module's export which is a table constructor.
@@ -55,6 +515,12 @@ pub struct Injector {
     exports: HashSet<String>,
     removes: Option<Vec<String>>,
     lua_version: LuaVersion,
+    inline_source: Option<String>,
+    extends: HashSet<String>,
+    require_style: RequireStyle,
+    output_root: PathBuf,
+    sandbox: Sandbox,
+    directives: HashMap<PathBuf, FileDirectives>,
 }
 
 impl Injector {
@@ -64,14 +530,57 @@ impl Injector {
         lua_version: LuaVersion,
         removes: Option<Vec<String>>,
     ) -> Self {
+        let output_root = module_path.parent().unwrap_or(Path::new("")).to_owned();
         Self {
             module_path,
             exports,
             removes,
             lua_version,
+            inline_source: None,
+            extends: HashSet::new(),
+            require_style: RequireStyle::default(),
+            output_root,
+            sandbox: Sandbox::default(),
+            directives: HashMap::new(),
         }
     }
 
+    /// Inlines the given module source into every injected file instead of requiring `module_path`.
+    ///
+    /// Each file only gets the exports it uses, written on a single line (see [`single_line`]).
+    pub fn with_inline_source(mut self, source: String) -> Self {
+        self.inline_source = Some(source);
+        self
+    }
+
+    /// Extends the given builtin libraries with the module's exports instead of shadowing them.
+    ///
+    /// The module extends them when loaded, so every file loads the module since method calls
+    /// like `("x"):split()` can't be detected.
+    pub fn with_extends(mut self, extends: HashSet<String>) -> Self {
+        self.extends = extends;
+        self
+    }
+
+    /// Requires the module with the given style. Dotted module paths start from `output_root`.
+    pub fn with_require_style(mut self, require_style: RequireStyle, output_root: PathBuf) -> Self {
+        self.require_style = require_style;
+        self.output_root = output_root;
+        self
+    }
+
+    /// Removes `removes` as configured by the sandbox instead of shadowing them with `nil`.
+    pub fn with_sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Honours directives of the files at the given absolute paths.
+    pub fn with_directives(mut self, directives: HashMap<PathBuf, FileDirectives>) -> Self {
+        self.directives = directives;
+        self
+    }
+
     pub fn module_path(&self) -> &PathBuf {
         &self.module_path
     }
@@ -80,56 +589,533 @@ impl Injector {
         &self.removes
     }
 
-    pub async fn inject(&self, source_path: &PathBuf) -> Result<()> {
-        let parent = source_path
+    /// Gets the dotted module path of the module from the output root.
+    fn module_name(&self) -> Result<String> {
+        let module_path = path::absolute(self.module_path())?;
+        let output_root = path::absolute(&self.output_root)?;
+        let module_path = module_path
+            .strip_prefix(&output_root)
+            .map_err(|_| {
+                anyhow!(
+                    "Polyfill module {:?} must be in the output root {:?} to be required by its module path",
+                    module_path,
+                    output_root
+                )
+            })?
+            .with_extension("");
+
+        Ok(module_path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("."))
+    }
+
+    /// Gets the expression that loads the module, given its path relative to the requiring file.
+    fn require_expression(&self, require_path: &Path) -> Result<String> {
+        let require_path = require_path.to_slash_lossy();
+        Ok(match &self.require_style {
+            RequireStyle::Relative => format!("require'{}'", require_path),
+            RequireStyle::Dotted => format!("require'{}'", self.module_name()?),
+            RequireStyle::Template(template) => {
+                let mut expression = template.replace("{path}", &require_path);
+                if expression.contains("{module}") {
+                    expression = expression.replace("{module}", &self.module_name()?);
+                }
+                expression
+            }
+        })
+    }
+
+    /// Gets the slash path of the module relative to the file at `source_path`, without extension.
+    fn require_path(&self, source_path: &Path) -> Result<PathBuf> {
+        // both sides must be absolute for `diff_paths` to resolve files in nested directories
+        let module_path = path::absolute(self.module_path())?;
+        let absolute_source_path = path::absolute(source_path)?;
+        let parent = absolute_source_path
             .parent()
             .ok_or(anyhow!("File path must have parent path"))?;
-        let require_path = diff_paths(self.module_path(), parent)
+        let require_path = diff_paths(&module_path, parent)
             .ok_or(anyhow!("Couldn't resolve the require path"))?
             .with_extension("");
-        let require_path = make_relative(&require_path).to_path_buf();
+        Ok(make_relative(&require_path).into_owned())
+    }
+
+    pub async fn inject(&self, source_path: &PathBuf) -> Result<()> {
+        let absolute_source_path = path::absolute(source_path)?;
+        let require_expression = self.require_expression(&self.require_path(source_path)?)?;
+
+        let directives = self.directives.get(&absolute_source_path);
+        if directives.is_some_and(FileDirectives::no_polyfill) {
+            log::debug!("skipped injecting {:?}", source_path);
+            return Ok(());
+        }
+        let lua_version = directives
+            .and_then(|directives| directives.target_version().as_ref())
+            .map_or(self.lua_version, TargetVersion::to_lua_version);
 
         let code = fs::read_to_string(source_path).await?;
+        let code = self.inject_code(code, lua_version, &require_expression)?;
+
+        log::debug!("injected source path: {:?}", source_path);
+
+        fs::write(source_path, code).await?;
+
+        Ok(())
+    }
+
+    /// Injects the header into the code of a file that requires the module with
+    /// `require_expression`, replacing a header injected by a previous run.
+    fn inject_code(
+        &self,
+        mut code: String,
+        lua_version: LuaVersion,
+        require_expression: &str,
+    ) -> Result<String> {
+        remove_header(&mut code);
 
-        let mut lines: Vec<String> = code.lines().map(String::from).collect();
         let mut libraries_texts: Vec<String> = Vec::new();
 
-        let ast = full_moon::parse_fallible(code.as_str(), self.lua_version)
-            .into_result()
-            .map_err(|errors| anyhow!("{:?}", errors))?;
+        let ast = utils::parse_code(&code, lua_version)?;
 
-        let mut collect_used_libs = CollectUsedLibraries::new(self.exports.clone());
+        // the `_ENV` sandbox removes globals without shadowing them with locals
+        let removes: &[String] = match self.removes() {
+            Some(removes) if !self.sandbox.env() => removes,
+            _ => &[],
+        };
+        let exports: HashSet<String> = self.exports.difference(&self.extends).cloned().collect();
+        // tables of a previous run's grouped header are collected too, to inject them again
+        let mut collect_used_libs = CollectUsedLibraries::new(
+            exports
+                .iter()
+                .chain(removes)
+                .cloned()
+                .chain([
+                    INLINE_MODULE_IDENTIFIER.to_owned(),
+                    REMOVED_IDENTIFIER.to_owned(),
+                ])
+                .collect(),
+        );
         collect_used_libs.visit_ast(&ast);
+        let grouped_module = collect_used_libs
+            .used_libraries
+            .contains(INLINE_MODULE_IDENTIFIER);
+        let grouped_removes = collect_used_libs
+            .used_libraries
+            .contains(REMOVED_IDENTIFIER);
+
+        let used_libraries: HashSet<String> = collect_used_libs
+            .used_libraries
+            .intersection(&exports)
+            .cloned()
+            .collect();
+        let loads_module = !used_libraries.is_empty() || !self.extends.is_empty() || grouped_module;
+
+        // injected locals, counting the module and the stub function as well
+        let injected_locals = used_libraries.len() + removes.len() + 2;
+        let exceeds_limits = grouped_module
+            || grouped_removes
+            || collect_used_libs.max_main_locals + injected_locals > MAX_LOCALS
+            || collect_used_libs.max_upvalues > max_upvalues(lua_version);
 
-        for lib in collect_used_libs.used_libraries {
-            log::debug!("used library: {}", lib);
+        if let Some(inline_source) = &self.inline_source {
+            if loads_module {
+                // references grouped by a previous run don't tell which exports are used
+                let module = if grouped_module {
+                    Cow::Borrowed(inline_source.as_str())
+                } else {
+                    Cow::Owned(shake_module(
+                        inline_source,
+                        &used_libraries,
+                        self.lua_version,
+                    )?)
+                };
+                libraries_texts.push(format!(
+                    "local {}=(function() {} end)() ",
+                    INLINE_MODULE_IDENTIFIER,
+                    single_line(&module, self.lua_version)?
+                ));
+            }
+        } else if loads_module && (used_libraries.is_empty() || exceeds_limits) {
             libraries_texts.push(format!(
-                "local {}=require'{}'.{} ",
-                lib,
-                require_path.to_slash_lossy(),
-                lib
+                "local {}={} ",
+                INLINE_MODULE_IDENTIFIER, require_expression
             ));
         }
 
-        if let Some(removes) = self.removes() {
-            for lib in removes {
-                libraries_texts.push(format!("local {}=nil ", lib));
+        if exceeds_limits {
+            log::info!(
+                "grouping injected globals in tables to stay within local and upvalue limits"
+            );
+            // references to removed globals may already have been rewritten by a previous run
+            let used_removes: Vec<String> = removes
+                .iter()
+                .filter(|name| grouped_removes || collect_used_libs.used_libraries.contains(*name))
+                .cloned()
+                .collect();
+            if !used_removes.is_empty() {
+                libraries_texts.push(
+                    self.sandbox
+                        .removes_table_code(&used_removes, REMOVED_IDENTIFIER),
+                );
+            }
+            // the `_ENV` sandbox only adds a single local, so it's kept as it is
+            if let Some(removes) = self.removes().as_ref().filter(|_| self.sandbox.env()) {
+                let removes_code = self.sandbox.removes_code(removes);
+                if !removes_code.is_empty() {
+                    libraries_texts.push(removes_code);
+                }
             }
-        }
 
-        let libraries_text = libraries_texts.join("");
-        if let Some(first_line) = lines.get_mut(0) {
-            first_line.insert_str(0, &libraries_text);
+            let mut references = collect_used_libs.references;
+            references.sort_by_key(|(_, range)| std::cmp::Reverse(range.start));
+            for (name, range) in references {
+                if name == INLINE_MODULE_IDENTIFIER || name == REMOVED_IDENTIFIER {
+                    continue;
+                }
+                let table = if used_removes.contains(&name) {
+                    REMOVED_IDENTIFIER
+                } else {
+                    INLINE_MODULE_IDENTIFIER
+                };
+                code.replace_range(range, &format!("{}.{}", table, name));
+            }
         } else {
-            lines.push(libraries_text);
+            for lib in used_libraries {
+                log::debug!("used library: {}", lib);
+                if self.inline_source.is_some() {
+                    libraries_texts.push(format!(
+                        "local {}={}.{} ",
+                        lib, INLINE_MODULE_IDENTIFIER, lib
+                    ));
+                } else {
+                    libraries_texts.push(format!("local {}={}.{} ", lib, require_expression, lib));
+                }
+            }
+
+            if let Some(removes) = self.removes() {
+                let removes_code = self.sandbox.removes_code(removes);
+                if !removes_code.is_empty() {
+                    libraries_texts.push(removes_code);
+                }
+            }
         }
 
-        let new_content = lines.join("\n");
+        if !libraries_texts.is_empty() {
+            insert_header(&mut code, header_offset(&ast), &libraries_texts);
+        }
 
-        log::debug!("injected source path: {:?}", source_path);
+        Ok(code)
+    }
+}
 
-        fs::write(source_path, new_content).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(())
+    fn parse(code: &str) -> Ast {
+        full_moon::parse_fallible(code, LuaVersion::luau())
+            .into_result()
+            .unwrap()
+    }
+
+    fn collect(code: &str, libraries: &[&str]) -> CollectUsedLibraries {
+        let mut collect_used_libs =
+            CollectUsedLibraries::new(libraries.iter().map(|name| name.to_string()).collect());
+        collect_used_libs.visit_ast(&parse(code));
+        collect_used_libs
+    }
+
+    fn used(code: &str, libraries: &[&str]) -> Vec<String> {
+        let mut used: Vec<String> = collect(code, libraries)
+            .used_libraries
+            .into_iter()
+            .collect();
+        used.sort();
+        used
+    }
+
+    #[test]
+    fn locals_shadow_libraries() {
+        assert!(used("local print = 1\nprint(x)", &["print"]).is_empty());
+        assert!(used("local function type() end\ntype(x)", &["type"]).is_empty());
+        assert!(used("local function f(next) return next end", &["next"]).is_empty());
+        assert!(used("for _, pairs in x do pairs() end", &["pairs"]).is_empty());
+        assert!(used("function obj:method() return self end", &["self"]).is_empty());
+        assert!(used("repeat local next = f() until next", &["next"]).is_empty());
+    }
+
+    #[test]
+    fn libraries_outside_local_scopes_are_used() {
+        assert_eq!(used("do local type = 1 end\ntype(x)", &["type"]), ["type"]);
+        assert_eq!(used("local print = print", &["print"]), ["print"]);
+        assert_eq!(
+            used(
+                "local function f(next) end\nreturn next, string.len",
+                &["next", "string"]
+            ),
+            ["next", "string"]
+        );
+        assert_eq!(used("function math.sign() end", &["math"]), ["math"]);
+        assert_eq!(
+            used(
+                "repeat local x = 1 until function() return x end\nreturn next",
+                &["next"]
+            ),
+            ["next"]
+        );
+        assert_eq!(
+            used("repeat repeat local a = 1 until a until type", &["type"]),
+            ["type"]
+        );
+        assert!(used("local t = { print = 1 }\nreturn t.print", &["print"]).is_empty());
+    }
+
+    #[test]
+    fn references_cover_identifiers() {
+        let code = "print(1) local x = print";
+        let references = collect(code, &["print"]).references;
+        assert_eq!(references.len(), 2);
+        for (name, range) in references {
+            assert_eq!(&code[range], name);
+        }
+    }
+
+    fn injector() -> Injector {
+        Injector::new(
+            PathBuf::from("__polyfill__.lua"),
+            HashSet::from(["print".to_owned()]),
+            LuaVersion::lua53(),
+            Some(vec!["io".to_owned()]),
+        )
+    }
+
+    fn inject(injector: &Injector, code: &str) -> String {
+        injector
+            .inject_code(
+                code.to_owned(),
+                LuaVersion::lua53(),
+                "require'./__polyfill__'",
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn inline_module_keeps_used_exports() {
+        let source = "local DALBIT_flag=true local unused=1\n\
+            local function helper() return DALBIT_flag end\n\
+            -- prints\n\
+            local function print(...) return helper() end\n\
+            local function warn() end\n\
+            setup()\n\
+            return { print = print, -- comment\n warn = warn }";
+        let module = shake_module(
+            source,
+            &HashSet::from(["print".to_owned()]),
+            LuaVersion::lua53(),
+        )
+        .unwrap();
+        let module = single_line(&module, LuaVersion::lua53()).unwrap();
+
+        assert!(module.contains("local DALBIT_flag=true"));
+        assert!(module.contains("local function helper()"));
+        assert!(module.contains("local function print(...)"));
+        assert!(module.contains("setup()"));
+        assert!(!module.contains("unused"));
+        assert!(!module.contains("warn"));
+        assert!(module.ends_with("return {print=print }"));
+    }
+
+    #[test]
+    fn inline_module_without_table_constructor_is_kept() {
+        let source = "local exports={} exports.print=print return exports";
+        let exports = HashSet::from(["print".to_owned()]);
+        assert_eq!(
+            shake_module(source, &exports, LuaVersion::lua53()).unwrap(),
+            source
+        );
+    }
+
+    fn require_expression(
+        style: RequireStyle,
+        module_path: &str,
+        source_path: &str,
+    ) -> Result<String> {
+        let injector = Injector::new(
+            PathBuf::from(module_path),
+            HashSet::new(),
+            LuaVersion::lua53(),
+            None,
+        )
+        .with_require_style(style, PathBuf::from("/out"));
+        injector.require_expression(&injector.require_path(Path::new(source_path))?)
+    }
+
+    #[test]
+    fn require_path_is_relative_to_nested_files() {
+        assert_eq!(
+            require_expression(
+                RequireStyle::Relative,
+                "/out/__polyfill__.lua",
+                "/out/main.lua"
+            )
+            .unwrap(),
+            "require'./__polyfill__'"
+        );
+        assert_eq!(
+            require_expression(
+                RequireStyle::Relative,
+                "/out/__polyfill__.lua",
+                "/out/a/b/main.lua"
+            )
+            .unwrap(),
+            "require'../../__polyfill__'"
+        );
+    }
+
+    #[test]
+    fn dotted_require_uses_module_path_from_output_root() {
+        assert_eq!(
+            require_expression(
+                RequireStyle::Dotted,
+                "/out/lib/__polyfill__.lua",
+                "/out/a/main.lua"
+            )
+            .unwrap(),
+            "require'lib.__polyfill__'"
+        );
+    }
+
+    #[test]
+    fn template_require_replaces_path_and_module() {
+        assert_eq!(
+            require_expression(
+                RequireStyle::Template("import('{path}', '{module}')".to_owned()),
+                "/out/lib/__polyfill__.lua",
+                "/out/a/main.lua"
+            )
+            .unwrap(),
+            "import('../lib/__polyfill__', 'lib.__polyfill__')"
+        );
+        // the module path is only required when the template uses it
+        assert_eq!(
+            require_expression(
+                RequireStyle::Template("load('{path}')".to_owned()),
+                "/elsewhere/__polyfill__.lua",
+                "/out/main.lua"
+            )
+            .unwrap(),
+            "load('../elsewhere/__polyfill__')"
+        );
+    }
+
+    #[test]
+    fn module_outside_output_root_has_no_module_path() {
+        let err = require_expression(
+            RequireStyle::Dotted,
+            "/elsewhere/__polyfill__.lua",
+            "/out/main.lua",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("must be in the output root"));
+        assert!(require_expression(
+            RequireStyle::Template("require'{module}'".to_owned()),
+            "/elsewhere/__polyfill__.lua",
+            "/out/main.lua"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn header_offset_skips_shebang_and_comments() {
+        assert_eq!(header_offset(&parse("local x")), 0);
+        assert_eq!(header_offset(&parse("#!/usr/bin/lua\nlocal x")), 15);
+        assert_eq!(header_offset(&parse("-- a\n--[[ b ]]\n\nlocal x")), 15);
+        assert_eq!(header_offset(&parse("--[[ a ]] local x")), 9);
+        assert_eq!(header_offset(&parse("-- a")), 4);
+    }
+
+    #[test]
+    fn header_keeps_line_numbers() {
+        let header = "--[[dalbit:polyfill]]local print=require'./__polyfill__'.print local io=nil --[[/dalbit:polyfill]]";
+        assert_eq!(
+            inject(&injector(), "print(1)\nlocal x\n"),
+            format!("{}print(1)\nlocal x\n", header)
+        );
+        assert_eq!(
+            inject(&injector(), "-- a\r\nprint(1)\r\n"),
+            format!("-- a\r\n{}print(1)\r\n", header)
+        );
+        assert_eq!(
+            inject(&injector(), "--[[ a ]] print(1)"),
+            format!("--[[ a ]]{} print(1)", header)
+        );
+        assert_eq!(
+            inject(&injector(), "-- a"),
+            "-- a\n--[[dalbit:polyfill]]local io=nil --[[/dalbit:polyfill]]"
+        );
+    }
+
+    #[test]
+    fn header_is_replaced() {
+        let injector = injector();
+        for code in ["print(1)\n", "-- a\r\nprint(1)", "-- a"] {
+            let injected = inject(&injector, code);
+            assert_eq!(inject(&injector, &injected), injected);
+        }
+    }
+
+    /// Code with enough locals that injected globals are grouped in tables.
+    fn many_locals_code(body: &str) -> String {
+        let mut code: String = (0..199).map(|i| format!("local v{}=0\n", i)).collect();
+        code.push_str(body);
+        code
+    }
+
+    #[test]
+    fn globals_are_grouped_past_local_limit() {
+        let injected = inject(&injector(), &many_locals_code("print(io)\n"));
+        let (header, body) = injected.split_once(HEADER_END).unwrap();
+        assert_eq!(
+            header,
+            "--[[dalbit:polyfill]]local _DALBIT_POLYFILL=require'./__polyfill__' local _DALBIT_REMOVED={} "
+        );
+        assert!(body.starts_with("local v0=0\n"));
+        assert!(body.ends_with("_DALBIT_POLYFILL.print(_DALBIT_REMOVED.io)\n"));
+    }
+
+    #[test]
+    fn grouped_globals_are_injected_again() {
+        let injector = injector();
+        let injected = inject(&injector, &many_locals_code("print(io)\n"));
+        assert_eq!(inject(&injector, &injected), injected);
+
+        let without_locals = "_DALBIT_POLYFILL.print(_DALBIT_REMOVED.io)\n";
+        assert!(inject(&injector, without_locals).starts_with(
+            "--[[dalbit:polyfill]]local _DALBIT_POLYFILL=require'./__polyfill__' local _DALBIT_REMOVED={} "
+        ));
+    }
+
+    #[test]
+    fn env_sandbox_is_kept_when_grouping() {
+        let sandbox: Sandbox = toml::from_str("env = true").unwrap();
+        let injector = injector().with_sandbox(sandbox);
+        let injected = inject(&injector, &many_locals_code("print(io)\n"));
+        let (header, body) = injected.split_once(HEADER_END).unwrap();
+        assert!(header.starts_with(
+            "--[[dalbit:polyfill]]local _DALBIT_POLYFILL=require'./__polyfill__' local _ENV="
+        ));
+        assert!(!header.contains("_DALBIT_REMOVED"));
+        assert!(body.ends_with("_DALBIT_POLYFILL.print(io)\n"));
+    }
+
+    #[test]
+    fn counts_main_locals_and_upvalues() {
+        let collect_used_libs = collect(
+            "local a, b = 1, 2\nlocal function f() local c = a + b return print(c) end",
+            &["print"],
+        );
+        assert_eq!(collect_used_libs.max_main_locals, 3);
+        assert_eq!(collect_used_libs.max_upvalues, 3);
     }
 }
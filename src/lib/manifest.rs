@@ -1,10 +1,11 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use tokio::fs;
+use toml_edit::{ser::ValueSerializer, DocumentMut, Item};
 
-use crate::{polyfill::Polyfill, TargetVersion};
+use crate::{modifiers::ModifierConfig, polyfill::Polyfill, sandbox::Sandbox, TargetVersion};
 
 
 /// Manifest for dalbit transpiler. This is a writable manifest.
@@ -15,9 +16,14 @@ pub struct Manifest {
     pub file_extension: Option<String>,
     pub target_version: TargetVersion,
     pub minify: bool,
-    pub modifiers: IndexMap<String, bool>,
+    pub modifiers: IndexMap<String, ModifierConfig>,
     pub polyfill: Polyfill,
-    pub bundle: bool
+    pub bundle: bool,
+    #[serde(default)]
+    pub sandbox: Sandbox,
+    /// Path the manifest was loaded from.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
 }
 
 impl Default for Manifest {
@@ -30,7 +36,9 @@ impl Default for Manifest {
             minify: true,
             modifiers: IndexMap::new(),
             polyfill: Polyfill::default(),
-            bundle: false
+            bundle: false,
+            sandbox: Sandbox::default(),
+            path: None,
         }
     }
 }
@@ -41,9 +49,9 @@ impl Manifest {
     pub async fn from_file(path: impl Into<PathBuf>) -> Result<Self> {
         let path = path.into();
         let manifest = fs::read_to_string(&path).await?;
-        let manifest: Manifest = toml::from_str(&manifest)
+        let mut manifest: Manifest = toml::from_str(&manifest)
             .with_context(|| format!("Failed to parse manifest file: {:?}", path))?;
-        // manifest.path = path;
+        manifest.path = Some(path);
         Ok(manifest)
     }
 
@@ -53,6 +61,37 @@ impl Manifest {
         Ok(())
     }
 
+    /// Writes the polyfill's `path` and `layers` into the manifest file at `path`, keeping the
+    /// rest of the file, including comments and formatting, as it is.
+    pub async fn write_polyfill_sources(&self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        let mut document: DocumentMut = fs::read_to_string(&path)
+            .await?
+            .parse()
+            .with_context(|| format!("Could not parse manifest file: {:?}", path))?;
+        let polyfill = document
+            .entry("polyfill")
+            .or_insert(toml_edit::table())
+            .as_table_like_mut()
+            .ok_or_else(|| anyhow!("`polyfill` of manifest {:?} is not a table", path))?;
+
+        if let Some(polyfill_path) = self.polyfill.path() {
+            let polyfill_path = polyfill_path.to_string_lossy();
+            polyfill.insert("path", toml_edit::value(polyfill_path.as_ref()));
+        } else {
+            polyfill.remove("path");
+        }
+        if self.polyfill.layers().is_empty() {
+            polyfill.remove("layers");
+        } else {
+            let layers = self.polyfill.layers().serialize(ValueSerializer::new())?;
+            polyfill.insert("layers", Item::Value(layers));
+        }
+
+        fs::write(path, document.to_string()).await?;
+        Ok(())
+    }
+
     #[inline]
     pub fn input(&self) -> &PathBuf {
         &self.input
@@ -69,7 +108,7 @@ impl Manifest {
     }
 
     #[inline]
-    pub fn modifiers(&self) -> &IndexMap<String, bool> {
+    pub fn modifiers(&self) -> &IndexMap<String, ModifierConfig> {
         &self.modifiers
     }
 
@@ -82,4 +121,14 @@ impl Manifest {
     pub fn polyfill(&self) -> &Polyfill {
         &self.polyfill
     }
+
+    #[inline]
+    pub fn sandbox(&self) -> &Sandbox {
+        &self.sandbox
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Option<PathBuf> {
+        &self.path
+    }
 }
\ No newline at end of file
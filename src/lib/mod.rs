@@ -1,31 +1,31 @@
 use full_moon::LuaVersion;
 use serde::{Deserialize, Serialize};
+use strum_macros::Display;
 
+pub mod authoring;
+pub mod directives;
 pub mod injector;
 pub mod manifest;
 pub mod modifiers;
 pub mod polyfill;
+pub mod sandbox;
 pub mod transpile;
 pub mod utils;
 
 /// Represents lua versions that implement serde
 #[non_exhaustive]
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Default, Display)]
 #[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
 pub enum TargetVersion {
     Lua51,
     Lua52,
     Lua53,
     Luau,
+    #[default]
     Default,
 }
 
-impl Default for TargetVersion {
-    fn default() -> Self {
-        TargetVersion::Default
-    }
-}
-
 impl TargetVersion {
     pub fn to_lua_version(&self) -> LuaVersion {
         match &self {
@@ -24,7 +24,7 @@ pub fn create_parentheses(inner_exp: Expression, contained: Option<ContainedSpan
 pub fn create_binary_operator(left: Expression, binop: BinOp, right: Expression) -> Expression {
     Expression::BinaryOperator {
         lhs: Box::new(left),
-        binop: binop,
+        binop,
         rhs: Box::new(right),
     }
 }
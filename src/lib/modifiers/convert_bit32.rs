@@ -1,6 +1,9 @@
 use std::string::ToString;
 use std::{collections::HashMap, str::FromStr};
 
+use darklua_core::rules::{
+    RuleConfiguration, RuleConfigurationError, RuleProperties, RulePropertyValue,
+};
 use full_moon::ast::punctuated::Pair;
 use full_moon::ast::Do;
 use full_moon::node::Node;
@@ -71,69 +74,67 @@ enum Bit32Method {
 
 impl Bit32Method {
     fn convert(&self, call: &Call) -> Option<Expression> {
-        if let Call::AnonymousCall(args) = call {
-            if let FunctionArgs::Parentheses {
-                parentheses,
-                arguments,
-            } = args
-            {
-                let mut iter = arguments.iter();
-                let first_arg = iter.next()?;
+        if let Call::AnonymousCall(FunctionArgs::Parentheses {
+            parentheses,
+            arguments,
+        }) = call
+        {
+            let mut iter = arguments.iter();
+            let first_arg = iter.next()?;
 
-                let binop = match self {
-                    Bit32Method::RightShift => {
-                        BinOp::DoubleGreaterThan(TokenReference::symbol(">>").unwrap())
-                    }
-                    Bit32Method::LeftShift => {
-                        BinOp::DoubleLessThan(TokenReference::symbol("<<").unwrap())
-                    }
-                    Bit32Method::And => BinOp::Ampersand(TokenReference::symbol("&").unwrap()),
-                    Bit32Method::Or => BinOp::Pipe(TokenReference::symbol("|").unwrap()),
-                    Bit32Method::Xor => BinOp::Tilde(TokenReference::symbol("~").unwrap()),
-                    Bit32Method::Not => {
-                        let masking_arg = mask_32bit(first_arg.clone());
-                        let parenthese = ast_util::create_parentheses(masking_arg, None);
-                        let bnot_exp = ast_util::create_unary_operator(
-                            UnOp::Tilde(TokenReference::symbol("~").unwrap()),
-                            parenthese,
-                        );
-                        let masking_unop = mask_32bit(bnot_exp);
-                        return Some(ast_util::create_parentheses(
-                            masking_unop,
-                            Some(parentheses.clone()),
-                        ));
-                    }
-                    Bit32Method::Test => {
-                        let second_arg = iter.next()?;
-                        let band_exp = ast_util::create_binary_operator(
-                            first_arg.clone(),
-                            BinOp::Ampersand(TokenReference::symbol("&").unwrap()),
-                            second_arg.clone(),
-                        );
-                        let parenthese = ast_util::create_parentheses(band_exp, None);
-                        let masking_bin_exp = mask_32bit(parenthese);
-                        let not_equal_exp = ast_util::create_binary_operator(
-                            masking_bin_exp,
-                            BinOp::TildeEqual(TokenReference::symbol("~=").unwrap()),
-                            ast_util::create_number("0"),
-                        );
-                        return Some(ast_util::create_parentheses(
-                            not_equal_exp,
-                            Some(parentheses.clone()),
-                        ));
-                    }
-                };
+            let binop = match self {
+                Bit32Method::RightShift => {
+                    BinOp::DoubleGreaterThan(TokenReference::symbol(">>").unwrap())
+                }
+                Bit32Method::LeftShift => {
+                    BinOp::DoubleLessThan(TokenReference::symbol("<<").unwrap())
+                }
+                Bit32Method::And => BinOp::Ampersand(TokenReference::symbol("&").unwrap()),
+                Bit32Method::Or => BinOp::Pipe(TokenReference::symbol("|").unwrap()),
+                Bit32Method::Xor => BinOp::Tilde(TokenReference::symbol("~").unwrap()),
+                Bit32Method::Not => {
+                    let masking_arg = mask_32bit(first_arg.clone());
+                    let parenthese = ast_util::create_parentheses(masking_arg, None);
+                    let bnot_exp = ast_util::create_unary_operator(
+                        UnOp::Tilde(TokenReference::symbol("~").unwrap()),
+                        parenthese,
+                    );
+                    let masking_unop = mask_32bit(bnot_exp);
+                    return Some(ast_util::create_parentheses(
+                        masking_unop,
+                        Some(parentheses.clone()),
+                    ));
+                }
+                Bit32Method::Test => {
+                    let second_arg = iter.next()?;
+                    let band_exp = ast_util::create_binary_operator(
+                        first_arg.clone(),
+                        BinOp::Ampersand(TokenReference::symbol("&").unwrap()),
+                        second_arg.clone(),
+                    );
+                    let parenthese = ast_util::create_parentheses(band_exp, None);
+                    let masking_bin_exp = mask_32bit(parenthese);
+                    let not_equal_exp = ast_util::create_binary_operator(
+                        masking_bin_exp,
+                        BinOp::TildeEqual(TokenReference::symbol("~=").unwrap()),
+                        ast_util::create_number("0"),
+                    );
+                    return Some(ast_util::create_parentheses(
+                        not_equal_exp,
+                        Some(parentheses.clone()),
+                    ));
+                }
+            };
 
-                let second_arg = iter.next()?;
-                let bitop_exp =
-                    ast_util::create_binary_operator(first_arg.clone(), binop, second_arg.clone());
-                let parenthese = ast_util::create_parentheses(bitop_exp, None);
-                let masking_bin_exp = mask_32bit(parenthese);
-                return Some(ast_util::create_parentheses(
-                    masking_bin_exp,
-                    Some(parentheses.clone()),
-                ));
-            }
+            let second_arg = iter.next()?;
+            let bitop_exp =
+                ast_util::create_binary_operator(first_arg.clone(), binop, second_arg.clone());
+            let parenthese = ast_util::create_parentheses(bitop_exp, None);
+            let masking_bin_exp = mask_32bit(parenthese);
+            return Some(ast_util::create_parentheses(
+                masking_bin_exp,
+                Some(parentheses.clone()),
+            ));
         }
         None
     }
@@ -154,73 +155,103 @@ impl Default for ConvertBit32 {
     }
 }
 
+impl RuleConfiguration for ConvertBit32 {
+    fn configure(&mut self, properties: RuleProperties) -> Result<(), RuleConfigurationError> {
+        for (key, value) in properties {
+            match (key.as_str(), value) {
+                ("bit32_identifier", RulePropertyValue::String(identifier)) => {
+                    self.bit32_identifier = identifier;
+                }
+                ("bit32_identifier", _) => {
+                    return Err(RuleConfigurationError::StringExpected(key));
+                }
+                _ => return Err(RuleConfigurationError::UnexpectedProperty(key)),
+            }
+        }
+        Ok(())
+    }
+
+    fn get_name(&self) -> &'static str {
+        CONVERT_BIT32_MODIFIER_NAME
+    }
+
+    fn serialize_to_properties(&self) -> RuleProperties {
+        let mut properties = RuleProperties::new();
+        if self.bit32_identifier != DEFAULT_BIT32_IDENTIFIER {
+            properties.insert(
+                "bit32_identifier".to_owned(),
+                RulePropertyValue::String(self.bit32_identifier.clone()),
+            );
+        }
+        properties
+    }
+}
+
 impl VisitorMut for ConvertBit32 {
     fn visit_stmt(&mut self, stmt: Stmt) -> Stmt {
         match &stmt {
-            Stmt::FunctionCall(func_call) => {
-                if let Some(_) = self.convert(func_call) {
-                    return Stmt::Do(
-                        Do::new()
-                            .with_do_token(TokenReference::new(
-                                func_call
-                                    .surrounding_trivia()
-                                    .0
-                                    .into_iter()
-                                    .cloned()
-                                    .collect(),
-                                Token::new(TokenType::Symbol { symbol: Symbol::Do }),
-                                vec![Token::new(TokenType::Whitespace {
-                                    characters: ShortString::new(" "),
-                                })],
-                            ))
-                            .with_end_token(TokenReference::new(
-                                Vec::new(),
-                                Token::new(TokenType::Symbol {
-                                    symbol: Symbol::End,
-                                }),
-                                func_call
-                                    .surrounding_trivia()
-                                    .1
-                                    .into_iter()
-                                    .cloned()
-                                    .collect(),
-                            )),
-                    );
-                }
+            Stmt::FunctionCall(func_call) if self.convert(func_call).is_some() => {
+                return Stmt::Do(
+                    Do::new()
+                        .with_do_token(TokenReference::new(
+                            func_call
+                                .surrounding_trivia()
+                                .0
+                                .into_iter()
+                                .cloned()
+                                .collect(),
+                            Token::new(TokenType::Symbol { symbol: Symbol::Do }),
+                            vec![Token::new(TokenType::Whitespace {
+                                characters: ShortString::new(" "),
+                            })],
+                        ))
+                        .with_end_token(TokenReference::new(
+                            Vec::new(),
+                            Token::new(TokenType::Symbol {
+                                symbol: Symbol::End,
+                            }),
+                            func_call
+                                .surrounding_trivia()
+                                .1
+                                .into_iter()
+                                .cloned()
+                                .collect(),
+                        )),
+                );
             }
-            Stmt::Assignment(assign) => {
-                if self.check_replaced(assign.variables(), assign.expressions()) {
-                    let mut do_trailing_trivia: Vec<Token> = Vec::new();
-                    for token_ref in assign.tokens() {
-                        for t in token_ref.leading_trivia() {
-                            do_trailing_trivia.push(t.to_owned());
-                        }
-                        for t in token_ref.trailing_trivia() {
-                            do_trailing_trivia.push(t.to_owned());
-                        }
+            Stmt::Assignment(assign)
+                if self.check_replaced(assign.variables(), assign.expressions()) =>
+            {
+                let mut do_trailing_trivia: Vec<Token> = Vec::new();
+                for token_ref in assign.tokens() {
+                    for t in token_ref.leading_trivia() {
+                        do_trailing_trivia.push(t.to_owned());
+                    }
+                    for t in token_ref.trailing_trivia() {
+                        do_trailing_trivia.push(t.to_owned());
                     }
-                    return Stmt::Do(
-                        Do::new()
-                            .with_do_token(TokenReference::new(
-                                vec![Token::new(TokenType::Whitespace {
-                                    characters: ShortString::new(" "),
-                                })],
-                                Token::new(TokenType::Symbol { symbol: Symbol::Do }),
-                                do_trailing_trivia,
-                            ))
-                            .with_end_token(TokenReference::new(
-                                vec![Token::new(TokenType::Whitespace {
-                                    characters: ShortString::new(" "),
-                                })],
-                                Token::new(TokenType::Symbol {
-                                    symbol: Symbol::End,
-                                }),
-                                vec![Token::new(TokenType::Whitespace {
-                                    characters: ShortString::new(" "),
-                                })],
-                            )),
-                    );
                 }
+                return Stmt::Do(
+                    Do::new()
+                        .with_do_token(TokenReference::new(
+                            vec![Token::new(TokenType::Whitespace {
+                                characters: ShortString::new(" "),
+                            })],
+                            Token::new(TokenType::Symbol { symbol: Symbol::Do }),
+                            do_trailing_trivia,
+                        ))
+                        .with_end_token(TokenReference::new(
+                            vec![Token::new(TokenType::Whitespace {
+                                characters: ShortString::new(" "),
+                            })],
+                            Token::new(TokenType::Symbol {
+                                symbol: Symbol::End,
+                            }),
+                            vec![Token::new(TokenType::Whitespace {
+                                characters: ShortString::new(" "),
+                            })],
+                        )),
+                );
             }
             Stmt::LocalAssignment(local_assign) => {
                 let mut variables: Punctuated<Var> = Punctuated::new();
@@ -297,25 +328,21 @@ impl ConvertBit32 {
                         }
                         let mut iter = var_exp.suffixes();
                         let first = iter.next();
-                        if let Some(first) = first {
-                            // there's an index(ex. `band`)
-                            if let Suffix::Index(index) = first {
-                                let index = index_to_string(index);
-                                if let Some(index) = index {
-                                    if let Ok(method) = Bit32Method::from_str(index.trim()) {
-                                        self.bit32_methods
-                                            .insert(var.to_string().trim().to_owned(), method);
-                                        return true;
-                                    }
+                        // there's an index(ex. `band`)
+                        if let Some(Suffix::Index(index)) = first {
+                            let index = index_to_string(index);
+                            if let Some(index) = index {
+                                if let Ok(method) = Bit32Method::from_str(index.trim()) {
+                                    self.bit32_methods
+                                        .insert(var.to_string().trim().to_owned(), method);
+                                    return true;
                                 }
                             }
                         }
                     }
-                    Var::Name(_) => {
-                        if self.is_bit32_identifier(exp.to_string().trim().to_owned()) {
-                            self.bit32_identifier = var.to_string().trim().to_owned();
-                            return true;
-                        }
+                    Var::Name(_) if self.is_bit32_identifier(exp.to_string().trim().to_owned()) => {
+                        self.bit32_identifier = var.to_string().trim().to_owned();
+                        return true;
                     }
                     _ => {}
                 }
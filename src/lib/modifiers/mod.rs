@@ -1,8 +1,10 @@
 use std::str::FromStr;
 
 use anyhow::{anyhow, Result};
-use darklua_core::rules::Rule;
+use darklua_core::rules::{Rule, RuleProperties};
 use full_moon::{ast::Ast, visitors::VisitorMut};
+use serde::{Deserialize, Serialize};
+use strum_macros::Display;
 
 pub(crate) mod ast_util;
 mod relative_path;
@@ -11,6 +13,7 @@ mod optimize_table_initializers;
 mod remove_generalized_iteration;
 mod remove_number_literals;
 mod remove_redeclared_keys;
+mod registry;
 mod runtime_identifier;
 mod get_love_modules;
 
@@ -21,6 +24,7 @@ pub use optimize_table_initializers::*;
 pub use remove_generalized_iteration::*;
 pub use remove_number_literals::*;
 pub use remove_redeclared_keys::*;
+pub use registry::*;
 pub use runtime_identifier::*;
 
 pub trait VisitorMutWrapper {
@@ -33,35 +37,107 @@ impl<T: VisitorMut> VisitorMutWrapper for T {
     }
 }
 
+/// Prefix of runtime helpers that only polyfills can provide.
+pub const POLYFILL_HELPER_PREFIX: &str = "__DALBIT_";
+
+/// Key of a modifier's table setting its phase instead of a property.
+pub const MODIFIER_PHASE_KEY: &str = "phase";
+
+/// Key of a modifier's table setting its order in its phase instead of a property.
+pub const MODIFIER_ORDER_KEY: &str = "order";
+
+/// Phases of the transpiling pipeline, run in declaration order.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ModifierPhase {
+    /// Runs on Luau code, before any lowering.
+    Pre,
+    /// Converts Luau features into Lua.
+    Lower,
+    Optimize,
+    Minify,
+    /// Runs last. Full_moon visitors default to this phase since darklua can't parse their output.
+    Post,
+}
+
+/// Modifier entry of the manifest: a flag, or an inline table of properties enabling it.
+///
+/// The table may also set the modifier's `phase` and `order` in the pipeline.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ModifierConfig {
+    Enabled(bool),
+    Properties(toml::Table),
+}
+
+impl ModifierConfig {
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, Self::Enabled(false))
+    }
+
+    /// Converts the inline table into rule properties.
+    pub fn properties(&self) -> Result<RuleProperties> {
+        match self {
+            Self::Enabled(_) => Ok(RuleProperties::new()),
+            Self::Properties(table) => {
+                let mut table = table.clone();
+                table.remove(MODIFIER_PHASE_KEY);
+                table.remove(MODIFIER_ORDER_KEY);
+                Ok(toml::Value::Table(table).try_into()?)
+            }
+        }
+    }
+
+    /// Gets the phase set in the inline table.
+    pub fn phase(&self) -> Result<Option<ModifierPhase>> {
+        self.pipeline_value(MODIFIER_PHASE_KEY)
+    }
+
+    /// Gets the order in its phase set in the inline table.
+    pub fn order(&self) -> Result<Option<u32>> {
+        self.pipeline_value(MODIFIER_ORDER_KEY)
+    }
+
+    fn pipeline_value<T: for<'de> Deserialize<'de>>(&self, key: &str) -> Result<Option<T>> {
+        let Self::Properties(table) = self else {
+            return Ok(None);
+        };
+        table
+            .get(key)
+            .map(|value| {
+                value
+                    .clone()
+                    .try_into()
+                    .map_err(|_| anyhow!("Invalid `{}` value {}", key, value))
+            })
+            .transpose()
+    }
+}
+
+impl From<bool> for ModifierConfig {
+    fn from(enabled: bool) -> Self {
+        Self::Enabled(enabled)
+    }
+}
+
 pub enum Modifier {
     DarkluaRule(Box<dyn Rule>),
     FullMoonVisitor(Box<dyn VisitorMutWrapper>),
 }
 
+impl Modifier {
+    /// Creates a modifier of the default registry, configured with the given properties.
+    pub fn new(name: &str, properties: RuleProperties) -> Result<Self> {
+        ModifierRegistry::default().create(name, properties)
+    }
+}
+
 impl FromStr for Modifier {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        let modifier = match s {
-            REMOVE_GENERALIZED_ITERATION_MODIFIER_NAME => {
-                Modifier::DarkluaRule(Box::<RemoveGeneralizedIteration>::default())
-            }
-            REMOVE_NUMBER_LITERALS_MODIFIER_NAME => {
-                Modifier::DarkluaRule(Box::<RemoveNumberLiterals>::default())
-            }
-            OPTIMIZE_TABLE_INITIALIZERS_MODIFIER_NAME => {
-                Modifier::DarkluaRule(Box::<OptimizeTableInitializers>::default())
-            }
-            CONVERT_BIT32_MODIFIER_NAME => Modifier::FullMoonVisitor(Box::new(
-                ConvertBit32::default(),
-            )
-                as Box<dyn VisitorMutWrapper>),
-            REMOVE_REDECLARED_KEYS_RULE_NAME => {
-                Modifier::DarkluaRule(Box::<RemoveRedeclaredKeys>::default())
-            }
-            _ => Modifier::DarkluaRule(s.parse::<Box<dyn Rule>>().map_err(|err| anyhow!(err))?),
-        };
-
-        Ok(modifier)
+        Self::new(s, RuleProperties::new())
     }
 }
@@ -4,12 +4,16 @@ use anyhow::anyhow;
 use darklua_core::{
     nodes::{Arguments, Block, Expression, Prefix, TableExpression},
     process::{DefaultVisitor, NodeProcessor, NodeVisitor},
-    rules::{Context, FlawlessRule, RuleConfiguration, RuleConfigurationError, RuleProperties},
+    rules::{
+        Context, FlawlessRule, RuleConfiguration, RuleConfigurationError, RuleProperties,
+        RulePropertyValue,
+    },
 };
 
 pub const OPTIMIZE_TABLE_INITIALIZERS_MODIFIER_NAME: &str = "optimize_table_initializers";
 
 const DEFAULT_TABLE_LIBRARY: &str = "table";
+const DEFAULT_OPTIMIZED_METHODS: [&str; 2] = ["create", "freeze"];
 
 #[non_exhaustive]
 enum OptimizableTableMethod {
@@ -60,9 +64,12 @@ impl FromStr for OptimizableTableMethod {
     }
 }
 
-struct Processor {}
+struct Processor<'a> {
+    table_identifier: &'a str,
+    methods: &'a [String],
+}
 
-impl NodeProcessor for Processor {
+impl NodeProcessor for Processor<'_> {
     fn process_expression(&mut self, exp: &mut Expression) {
         if let Expression::Call(func_call) = exp {
             let lib_and_call: Option<(&str, &str)> = match func_call.get_prefix() {
@@ -87,7 +94,9 @@ impl NodeProcessor for Processor {
                 _ => None,
             };
             if let Some((lib_name, call_name)) = lib_and_call {
-                if lib_name != DEFAULT_TABLE_LIBRARY {
+                if lib_name != self.table_identifier
+                    || !self.methods.iter().any(|method| method == call_name)
+                {
                     return;
                 }
                 if let Ok(method) = OptimizableTableMethod::from_str(call_name) {
@@ -101,18 +110,60 @@ impl NodeProcessor for Processor {
     }
 }
 
-#[derive(Default, Debug)]
-pub struct OptimizeTableInitializers {}
+#[derive(Debug)]
+pub struct OptimizeTableInitializers {
+    /// Identifier of the table library whose calls are optimized.
+    table_identifier: String,
+    /// Names of the optimized methods.
+    methods: Vec<String>,
+}
+
+impl Default for OptimizeTableInitializers {
+    fn default() -> Self {
+        Self {
+            table_identifier: DEFAULT_TABLE_LIBRARY.to_owned(),
+            methods: DEFAULT_OPTIMIZED_METHODS.map(str::to_owned).to_vec(),
+        }
+    }
+}
 
 impl FlawlessRule for OptimizeTableInitializers {
     fn flawless_process(&self, block: &mut Block, _: &Context) {
-        let mut processor = Processor {};
+        let mut processor = Processor {
+            table_identifier: &self.table_identifier,
+            methods: &self.methods,
+        };
         DefaultVisitor::visit_block(block, &mut processor);
     }
 }
 
 impl RuleConfiguration for OptimizeTableInitializers {
-    fn configure(&mut self, _: RuleProperties) -> Result<(), RuleConfigurationError> {
+    fn configure(&mut self, properties: RuleProperties) -> Result<(), RuleConfigurationError> {
+        for (key, value) in properties {
+            match (key.as_str(), value) {
+                ("table_identifier", RulePropertyValue::String(identifier)) => {
+                    self.table_identifier = identifier;
+                }
+                ("table_identifier", _) => {
+                    return Err(RuleConfigurationError::StringExpected(key));
+                }
+                ("methods", RulePropertyValue::StringList(methods)) => {
+                    for method in &methods {
+                        OptimizableTableMethod::from_str(method).map_err(|err| {
+                            RuleConfigurationError::UnexpectedValue {
+                                property: key.clone(),
+                                message: err.to_string(),
+                            }
+                        })?;
+                    }
+                    self.methods = methods;
+                }
+                ("methods", _) => {
+                    return Err(RuleConfigurationError::StringListExpected(key));
+                }
+                _ => return Err(RuleConfigurationError::UnexpectedProperty(key)),
+            }
+        }
         Ok(())
     }
 
@@ -121,6 +172,19 @@ impl RuleConfiguration for OptimizeTableInitializers {
     }
 
     fn serialize_to_properties(&self) -> darklua_core::rules::RuleProperties {
-        RuleProperties::new()
+        let mut properties = RuleProperties::new();
+        if self.table_identifier != DEFAULT_TABLE_LIBRARY {
+            properties.insert(
+                "table_identifier".to_owned(),
+                RulePropertyValue::String(self.table_identifier.clone()),
+            );
+        }
+        if self.methods != DEFAULT_OPTIMIZED_METHODS {
+            properties.insert(
+                "methods".to_owned(),
+                RulePropertyValue::StringList(self.methods.clone()),
+            );
+        }
+        properties
     }
 }
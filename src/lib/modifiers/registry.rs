@@ -0,0 +1,229 @@
+use anyhow::{anyhow, Result};
+use darklua_core::rules::{Rule, RuleConfiguration, RuleConfigurationError, RuleProperties};
+use full_moon::visitors::VisitorMut;
+use indexmap::IndexMap;
+
+use crate::TargetVersion;
+
+use super::{
+    ConvertBit32, Modifier, ModifierPhase, OptimizeTableInitializers, RemoveGeneralizedIteration,
+    RemoveNumberLiterals, RemoveRedeclaredKeys, REMOVE_GENERALIZED_ITERATION_REQUIRED_GLOBALS,
+};
+
+/// Creates a modifier from the properties given in the manifest.
+pub type ModifierFactory = Box<dyn Fn(RuleProperties) -> Result<Modifier> + Send + Sync>;
+
+/// Metadata of a registered modifier.
+#[derive(Debug, Clone, Default)]
+pub struct ModifierInfo {
+    description: String,
+    /// Target versions the modifier supports. Every target version is supported when empty.
+    targets: Vec<TargetVersion>,
+    /// Phase the modifier runs in unless the manifest sets one.
+    phase: Option<ModifierPhase>,
+    /// Globals that the code generated by the modifier requires at runtime.
+    required_globals: Vec<String>,
+}
+
+impl ModifierInfo {
+    pub fn new(description: impl Into<String>) -> Self {
+        Self {
+            description: description.into(),
+            targets: Vec::new(),
+            phase: None,
+            required_globals: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn with_targets(mut self, targets: impl IntoIterator<Item = TargetVersion>) -> Self {
+        self.targets = targets.into_iter().collect();
+        self
+    }
+
+    #[inline]
+    pub fn with_phase(mut self, phase: ModifierPhase) -> Self {
+        self.phase = Some(phase);
+        self
+    }
+
+    #[inline]
+    pub fn with_required_globals(
+        mut self,
+        required_globals: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.required_globals = required_globals.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Whether the modifier can be used for the given target version.
+    #[inline]
+    pub fn supports(&self, target_version: &TargetVersion) -> bool {
+        self.targets.is_empty() || self.targets.contains(target_version)
+    }
+
+    #[inline]
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    #[inline]
+    pub fn targets(&self) -> &Vec<TargetVersion> {
+        &self.targets
+    }
+
+    #[inline]
+    pub fn phase(&self) -> Option<ModifierPhase> {
+        self.phase
+    }
+
+    #[inline]
+    pub fn required_globals(&self) -> &Vec<String> {
+        &self.required_globals
+    }
+}
+
+struct RegisteredModifier {
+    info: ModifierInfo,
+    factory: ModifierFactory,
+}
+
+fn configure(
+    name: &str,
+    modifier: &mut dyn RuleConfiguration,
+    properties: RuleProperties,
+) -> Result<()> {
+    // some darklua rules require properties, so defaults are kept as they are
+    if properties.is_empty() {
+        return Ok(());
+    }
+    modifier
+        .configure(properties)
+        .map_err(|err: RuleConfigurationError| {
+            anyhow!("Invalid properties of modifier `{}`: {}", name, err)
+        })
+}
+
+/// Modifiers that manifests can enable by name.
+///
+/// Dalbit's modifiers are registered by default, and names that aren't registered are resolved
+/// as darklua rules.
+pub struct ModifierRegistry {
+    modifiers: IndexMap<String, RegisteredModifier>,
+}
+
+impl Default for ModifierRegistry {
+    fn default() -> Self {
+        Self {
+            modifiers: IndexMap::new(),
+        }
+        .with_rule::<RemoveGeneralizedIteration>(
+            ModifierInfo::new("Converts generalized iterations into `__iter` and `next` calls")
+                .with_required_globals(REMOVE_GENERALIZED_ITERATION_REQUIRED_GLOBALS),
+        )
+        .with_rule::<RemoveNumberLiterals>(ModifierInfo::new(
+            "Converts binary and underscored number literals into decimal ones",
+        ))
+        .with_rule::<OptimizeTableInitializers>(
+            ModifierInfo::new(
+                "Replaces `table.create` and `table.freeze` calls with table constructors",
+            )
+            .with_phase(ModifierPhase::Optimize),
+        )
+        .with_rule::<RemoveRedeclaredKeys>(ModifierInfo::new(
+            "Removes keys declared more than once in table constructors",
+        ))
+        .with_visitor::<ConvertBit32>(
+            ModifierInfo::new("Converts `bit32` calls into bitwise operators")
+                .with_targets([TargetVersion::Lua53]),
+        )
+    }
+}
+
+impl ModifierRegistry {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a modifier created by the given factory.
+    pub fn with_modifier(
+        mut self,
+        name: impl Into<String>,
+        info: ModifierInfo,
+        factory: impl Fn(RuleProperties) -> Result<Modifier> + Send + Sync + 'static,
+    ) -> Self {
+        self.modifiers.insert(
+            name.into(),
+            RegisteredModifier {
+                info,
+                factory: Box::new(factory),
+            },
+        );
+        self
+    }
+
+    /// Registers a darklua rule under its name.
+    pub fn with_rule<R: Rule + Default + 'static>(self, info: ModifierInfo) -> Self {
+        let name = R::default().get_name();
+        self.with_modifier(name, info, move |properties| {
+            let mut rule = R::default();
+            configure(name, &mut rule, properties)?;
+            Ok(Modifier::DarkluaRule(Box::new(rule)))
+        })
+    }
+
+    /// Registers a full_moon visitor under its name. It runs in the `post` phase unless `info`
+    /// sets another phase.
+    pub fn with_visitor<V: VisitorMut + RuleConfiguration + Default + 'static>(
+        self,
+        mut info: ModifierInfo,
+    ) -> Self {
+        if info.phase.is_none() {
+            info.phase = Some(ModifierPhase::Post);
+        }
+        let name = V::default().get_name();
+        self.with_modifier(name, info, move |properties| {
+            let mut visitor = V::default();
+            configure(name, &mut visitor, properties)?;
+            Ok(Modifier::FullMoonVisitor(Box::new(visitor)))
+        })
+    }
+
+    /// Creates the modifier of the given name, configured with the given properties.
+    pub fn create(&self, name: &str, properties: RuleProperties) -> Result<Modifier> {
+        if let Some(modifier) = self.modifiers.get(name) {
+            return (modifier.factory)(properties);
+        }
+        let mut rule = name.parse::<Box<dyn Rule>>().map_err(|err| anyhow!(err))?;
+        configure(name, rule.as_mut(), properties)?;
+        Ok(Modifier::DarkluaRule(rule))
+    }
+
+    /// Checks whether the modifier can be used for the given target version.
+    pub fn check_target(&self, name: &str, target_version: &TargetVersion) -> Result<()> {
+        match self.info(name) {
+            Some(info) if !info.supports(target_version) => Err(anyhow!(
+                "Modifier `{}` doesn't support target version `{}`",
+                name,
+                target_version
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the name is a registered modifier or a darklua rule.
+    pub fn contains(&self, name: &str) -> bool {
+        self.modifiers.contains_key(name) || name.parse::<Box<dyn Rule>>().is_ok()
+    }
+
+    #[inline]
+    pub fn info(&self, name: &str) -> Option<&ModifierInfo> {
+        self.modifiers.get(name).map(|modifier| &modifier.info)
+    }
+
+    /// Gets names of the registered modifiers, in registration order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.modifiers.keys().map(String::as_str)
+    }
+}
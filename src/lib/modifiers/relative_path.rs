@@ -34,9 +34,9 @@ impl<'a> NodeProcessor for Processor<'a> {
                                 .strip_prefix(self.project_root)
                                 .expect("Path strip failed.");
                             *expr = StringExpression::from_value(
-                                &*new_path
+                                new_path
                                     .to_path_buf()
-                                    .into_iter()
+                                    .iter()
                                     .map(|x| x.to_str().unwrap())
                                     .collect::<Vec<&str>>()
                                     .join(".")
@@ -4,7 +4,9 @@ use darklua_core::nodes::{
     Statement, StringExpression, TupleArguments, TypedIdentifier, Variable,
 };
 use darklua_core::process::{DefaultVisitor, NodeProcessor, NodeVisitor};
-use darklua_core::rules::{Context, RuleConfiguration, RuleConfigurationError, RuleProperties};
+use darklua_core::rules::{
+    Context, RuleConfiguration, RuleConfigurationError, RuleProperties, RulePropertyValue,
+};
 
 use super::runtime_identifier::RuntimeIdentifierBuilder;
 use darklua_core::rules::{Rule, RuleProcessResult};
@@ -174,6 +176,10 @@ impl NodeProcessor for Processor {
 
 pub const REMOVE_GENERALIZED_ITERATION_MODIFIER_NAME: &str = "remove_generalized_iteration";
 
+/// Globals called by the code this modifier generates.
+pub const REMOVE_GENERALIZED_ITERATION_REQUIRED_GLOBALS: [&str; 3] =
+    [GETMETATABLE_IDENTIFIER, "type", "next"];
+
 /// A rule that removes generalized iteration.
 #[derive(Debug, PartialEq, Eq)]
 pub struct RemoveGeneralizedIteration {
@@ -208,7 +214,18 @@ impl Rule for RemoveGeneralizedIteration {
 }
 
 impl RuleConfiguration for RemoveGeneralizedIteration {
-    fn configure(&mut self, _: RuleProperties) -> Result<(), RuleConfigurationError> {
+    fn configure(&mut self, properties: RuleProperties) -> Result<(), RuleConfigurationError> {
+        for (key, value) in properties {
+            match (key.as_str(), value) {
+                ("runtime_identifier_format", RulePropertyValue::String(format)) => {
+                    self.runtime_identifier_format = format;
+                }
+                ("runtime_identifier_format", _) => {
+                    return Err(RuleConfigurationError::StringExpected(key));
+                }
+                _ => return Err(RuleConfigurationError::UnexpectedProperty(key)),
+            }
+        }
         Ok(())
     }
 
@@ -217,6 +234,13 @@ impl RuleConfiguration for RemoveGeneralizedIteration {
     }
 
     fn serialize_to_properties(&self) -> RuleProperties {
-        RuleProperties::new()
+        let mut properties = RuleProperties::new();
+        if self.runtime_identifier_format != Self::default().runtime_identifier_format {
+            properties.insert(
+                "runtime_identifier_format".to_owned(),
+                RulePropertyValue::String(self.runtime_identifier_format.clone()),
+            );
+        }
+        properties
     }
 }
@@ -37,8 +37,11 @@ impl FlawlessRule for RemoveNumberLiterals {
 }
 
 impl RuleConfiguration for RemoveNumberLiterals {
-    fn configure(&mut self, _: RuleProperties) -> Result<(), RuleConfigurationError> {
-        Ok(())
+    fn configure(&mut self, properties: RuleProperties) -> Result<(), RuleConfigurationError> {
+        match properties.into_keys().next() {
+            Some(key) => Err(RuleConfigurationError::UnexpectedProperty(key)),
+            None => Ok(()),
+        }
     }
 
     fn get_name(&self) -> &'static str {
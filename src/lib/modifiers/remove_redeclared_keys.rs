@@ -4,7 +4,9 @@ use darklua_core::nodes::{
     StringExpression, TableEntry, TableExpression,
 };
 use darklua_core::process::{DefaultVisitor, Evaluator, LuaValue, NodeProcessor, NodeVisitor};
-use darklua_core::rules::{Context, RuleConfiguration, RuleConfigurationError, RuleProperties};
+use darklua_core::rules::{
+    Context, RuleConfiguration, RuleConfigurationError, RuleProperties, RulePropertyValue,
+};
 
 use super::runtime_identifier::RuntimeIdentifierBuilder;
 use darklua_core::rules::{Rule, RuleProcessResult};
@@ -43,21 +45,21 @@ impl NodeProcessor for Processor {
                     TableEntry::Index(index_entry) => {
                         let value = self.evaluator.evaluate(index_entry.get_key());
                         match value {
-                            LuaValue::Number(lua_index) => {
-                                if lua_index.fract() == 0.0 && lua_index > 0.0 {
-                                    let key = (lua_index as usize) - 1;
-                                    if side_effect_stmts.is_empty() {
-                                        numeral_table.insert(key, i);
-                                    } else {
-                                        let assignment = AssignStatement::from_variable(
-                                            IndexExpression::new(
-                                                Identifier::new(self.table_identifier.as_str()),
-                                                key + 1,
-                                            ),
-                                            index_entry.get_value().clone(),
-                                        );
-                                        side_effect_stmts.push(assignment.into());
-                                    }
+                            LuaValue::Number(lua_index)
+                                if lua_index.fract() == 0.0 && lua_index > 0.0 =>
+                            {
+                                let key = (lua_index as usize) - 1;
+                                if side_effect_stmts.is_empty() {
+                                    numeral_table.insert(key, i);
+                                } else {
+                                    let assignment = AssignStatement::from_variable(
+                                        IndexExpression::new(
+                                            Identifier::new(self.table_identifier.as_str()),
+                                            key + 1,
+                                        ),
+                                        index_entry.get_value().clone(),
+                                    );
+                                    side_effect_stmts.push(assignment.into());
                                 }
                             }
                             LuaValue::String(key) => {
@@ -184,7 +186,18 @@ impl Rule for RemoveRedeclaredKeys {
 }
 
 impl RuleConfiguration for RemoveRedeclaredKeys {
-    fn configure(&mut self, _: RuleProperties) -> Result<(), RuleConfigurationError> {
+    fn configure(&mut self, properties: RuleProperties) -> Result<(), RuleConfigurationError> {
+        for (key, value) in properties {
+            match (key.as_str(), value) {
+                ("runtime_identifier_format", RulePropertyValue::String(format)) => {
+                    self.runtime_identifier_format = format;
+                }
+                ("runtime_identifier_format", _) => {
+                    return Err(RuleConfigurationError::StringExpected(key));
+                }
+                _ => return Err(RuleConfigurationError::UnexpectedProperty(key)),
+            }
+        }
         Ok(())
     }
 
@@ -193,6 +206,13 @@ impl RuleConfiguration for RemoveRedeclaredKeys {
     }
 
     fn serialize_to_properties(&self) -> RuleProperties {
-        RuleProperties::new()
+        let mut properties = RuleProperties::new();
+        if self.runtime_identifier_format != Self::default().runtime_identifier_format {
+            properties.insert(
+                "runtime_identifier_format".to_owned(),
+                RulePropertyValue::String(self.runtime_identifier_format.clone()),
+            );
+        }
+        properties
     }
 }
@@ -6,61 +6,478 @@ use dirs;
 use fs_err;
 use git2::Repository;
 use hex;
+use semver::Version;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env;
+use std::fmt;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
 use tokio::fs;
 use url::Url;
 
-use crate::{utils, TargetVersion};
+use crate::{manifest::Manifest, utils, TargetVersion};
 
 pub const DEFAULT_REPO_URL: &str = "https://github.com/CavefulGames/dalbit-polyfill";
 pub const DEFAULT_INJECTION_PATH: &str = "__polyfill__";
 
+pub const CACHE_DIR_ENV_VAR: &str = "DALBIT_CACHE_DIR";
+
+const REFERENCES_FILE_NAME: &str = "references.toml";
+/// Lock file of the whole cache directory, held exclusively while caches are cleaned.
+const CACHE_LOCK_FILE_NAME: &str = ".lock";
+/// File recording where a vendored polyfill was copied from.
+const VENDOR_SOURCE_FILE_NAME: &str = ".dalbit-vendor";
+
+static CACHE_DIR_OVERRIDE: RwLock<Option<PathBuf>> = RwLock::new(None);
+
 /// Cleans cache from polyfill repository url.
-pub async fn clean_cache(url: &Url) -> Result<()> {
-    let index_path = index_path(url)?;
+pub async fn clean_cache(cache_dir: &Path, url: &Url) -> Result<()> {
+    let index_path = index_path(cache_dir, url);
+    let _lock = lock_index(&index_path)?;
     fs::remove_dir_all(index_path).await?;
     Ok(())
 }
 
+/// Cleans caches of polyfills that are not referenced by any known manifest.
+///
+/// With `older_than`, only caches that have not been used for that long are cleaned.
+/// Returns paths of the removed caches.
+pub async fn clean_cache_unused(
+    cache_dir: &Path,
+    older_than: Option<Duration>,
+) -> Result<Vec<PathBuf>> {
+    if !cache_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    // no cache is in use while the cache directory is locked, so lock files can be removed too
+    let _lock = lock_cache_dir(cache_dir)?;
+    let mut references = CacheReferences::load(cache_dir).await?;
+    references.retain_existing().await;
+    references.write(cache_dir).await?;
+
+    let mut removed = Vec::new();
+    let mut entries = fs::read_dir(cache_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if references.is_referenced(&name) {
+            continue;
+        }
+
+        if let Some(older_than) = older_than {
+            // the lock file is touched whenever the cache is used
+            let last_used = fs_err::metadata(index_lock_path(&path))
+                .or_else(|_| fs_err::metadata(&path))?
+                .modified()?;
+            if last_used.elapsed().unwrap_or_default() < older_than {
+                continue;
+            }
+        }
+
+        log::info!("removing unused polyfill cache {:?}", path);
+        fs::remove_dir_all(&path).await?;
+        match fs::remove_file(index_lock_path(&path)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        removed.push(path);
+    }
+
+    Ok(removed)
+}
+
+/// Records that the manifest at `manifest_path` references the polyfill caches of `polyfill`.
+///
+/// Caches referenced by an existing manifest are kept by [`clean_cache_unused`].
+pub async fn add_references(manifest_path: &Path, polyfill: &Polyfill) -> Result<()> {
+    let manifest_path = std::path::absolute(manifest_path)?;
+    let cache_dir = polyfill.cache_dir()?;
+    let _lock = lock_index(&cache_dir.join(REFERENCES_FILE_NAME))?;
+    let mut references = CacheReferences::load(&cache_dir).await?;
+    for source in polyfill.sources() {
+        let Some(repository) = source.repository() else {
+            continue;
+        };
+        references
+            .references
+            .entry(index_name(repository))
+            .or_default()
+            .insert(manifest_path.clone());
+    }
+    references.write(&cache_dir).await
+}
+
+/// Manifests that reference each polyfill cache, keyed by cache directory name.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct CacheReferences {
+    #[serde(default)]
+    references: BTreeMap<String, BTreeSet<PathBuf>>,
+}
+
+impl CacheReferences {
+    async fn load(cache_dir: &Path) -> Result<Self> {
+        let path = cache_dir.join(REFERENCES_FILE_NAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(&path).await?;
+        toml::from_str(&content)
+            .with_context(|| format!("Could not parse polyfill cache references: {:?}", path))
+    }
+
+    async fn write(&self, cache_dir: &Path) -> Result<()> {
+        let path = cache_dir.join(REFERENCES_FILE_NAME);
+        fs::write(path, toml::to_string(self)?).await?;
+        Ok(())
+    }
+
+    /// Drops manifests that no longer exist or no longer reference the cache.
+    async fn retain_existing(&mut self) {
+        let mut manifest_paths: BTreeSet<PathBuf> = BTreeSet::new();
+        for paths in self.references.values() {
+            manifest_paths.extend(paths.iter().cloned());
+        }
+
+        let mut indexes: HashMap<PathBuf, HashSet<String>> = HashMap::new();
+        for manifest_path in manifest_paths {
+            if let Ok(manifest) = Manifest::from_file(&manifest_path).await {
+                indexes.insert(
+                    manifest_path,
+                    manifest
+                        .polyfill()
+                        .sources()
+                        .iter()
+                        .filter_map(|source| source.repository().map(index_name))
+                        .collect(),
+                );
+            }
+        }
+
+        for (name, paths) in self.references.iter_mut() {
+            paths.retain(|path| indexes.get(path).is_some_and(|names| names.contains(name)));
+        }
+        self.references.retain(|_, paths| !paths.is_empty());
+    }
+
+    #[inline]
+    fn is_referenced(&self, name: &str) -> bool {
+        self.references.contains_key(name)
+    }
+}
+
 /// Cleans every caches of polyfill.
-pub async fn clean_cache_all() -> Result<()> {
-    let path = cache_dir()?;
-    fs::remove_dir_all(path).await?;
+///
+/// The cache directory itself is kept, with the lock file other processes may be waiting on.
+pub async fn clean_cache_all(cache_dir: &Path) -> Result<()> {
+    if !cache_dir.exists() {
+        return Ok(());
+    }
+
+    let _lock = lock_cache_dir(cache_dir)?;
+    let mut entries = fs::read_dir(cache_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name() == CACHE_LOCK_FILE_NAME {
+            continue;
+        }
+        let path = entry.path();
+        if path.is_dir() {
+            fs::remove_dir_all(&path).await?;
+        } else {
+            fs::remove_file(&path).await?;
+        }
+    }
     Ok(())
 }
 
+/// Overrides cache directory of polyfills for this process. (ex. from `--cache-dir`)
+pub fn set_cache_dir(path: Option<PathBuf>) {
+    *CACHE_DIR_OVERRIDE.write().unwrap() = path;
+}
+
 /// Gets cache directory path of polyfills.
 pub fn cache_dir() -> Result<PathBuf> {
+    resolve_cache_dir(None)
+}
+
+/// Resolves cache directory from `set_cache_dir`, `DALBIT_CACHE_DIR`, the manifest's value and
+/// the user's cache directory, in that order.
+fn resolve_cache_dir(manifest_cache_dir: Option<&Path>) -> Result<PathBuf> {
+    if let Some(path) = CACHE_DIR_OVERRIDE.read().unwrap().as_ref() {
+        return Ok(path.to_owned());
+    }
+    if let Some(path) = env::var_os(CACHE_DIR_ENV_VAR).filter(|path| !path.is_empty()) {
+        return Ok(PathBuf::from(path));
+    }
+    if let Some(path) = manifest_cache_dir {
+        return Ok(path.to_owned());
+    }
     Ok(dirs::cache_dir()
         .ok_or_else(|| anyhow!("could not find cache directory"))?
         .join("dalbit")
         .join("polyfills"))
 }
 
+/// Empty table, which is how `nil` is written in TOML.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EmptyTable {}
+
+/// Deserialized form of [`ConfigValue`] that only accepts an empty table as `nil`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawConfigValue {
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Nil(EmptyTable),
+}
+
+impl From<RawConfigValue> for ConfigValue {
+    fn from(value: RawConfigValue) -> Self {
+        match value {
+            RawConfigValue::Boolean(value) => ConfigValue::Boolean(value),
+            RawConfigValue::Number(value) => ConfigValue::Number(value),
+            RawConfigValue::String(value) => ConfigValue::String(value),
+            RawConfigValue::Nil(EmptyTable {}) => ConfigValue::Nil {},
+        }
+    }
+}
+
+/// Value of a polyfill config entry. `nil` is written as an empty table (`{}`).
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged, from = "RawConfigValue")]
+pub enum ConfigValue {
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Nil {},
+}
+
+impl ConfigValue {
+    /// Gets lua type name of the value.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ConfigValue::Boolean(_) => "boolean",
+            ConfigValue::Number(_) => "number",
+            ConfigValue::String(_) => "string",
+            ConfigValue::Nil {} => "nil",
+        }
+    }
+
+    /// Gets the value as a Lua literal.
+    pub fn to_lua(&self) -> String {
+        match self {
+            ConfigValue::Boolean(value) => value.to_string(),
+            ConfigValue::Number(value) if value.is_nan() => "(0/0)".to_owned(),
+            ConfigValue::Number(value) if value.is_infinite() => {
+                if *value > 0.0 { "(1/0)" } else { "(-1/0)" }.to_owned()
+            }
+            ConfigValue::Number(value) => value.to_string(),
+            ConfigValue::String(value) => {
+                let mut literal = String::from("\"");
+                for char in value.chars() {
+                    match char {
+                        '"' => literal.push_str("\\\""),
+                        '\\' => literal.push_str("\\\\"),
+                        '\n' => literal.push_str("\\n"),
+                        '\r' => literal.push_str("\\r"),
+                        char if char.is_ascii_control() => {
+                            literal.push_str(&format!("\\{:03}", char as u32))
+                        }
+                        char => literal.push(char),
+                    }
+                }
+                literal.push('"');
+                literal
+            }
+            ConfigValue::Nil {} => "nil".to_owned(),
+        }
+    }
+
+    /// Whether this value can override the given default. `nil` is compatible with every type.
+    pub fn is_compatible_with(&self, default: &ConfigValue) -> bool {
+        matches!(self, ConfigValue::Nil {})
+            || matches!(default, ConfigValue::Nil {})
+            || self.type_name() == default.type_name()
+    }
+}
+
+impl fmt::Display for ConfigValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigValue::Boolean(value) => write!(f, "{}", value),
+            ConfigValue::Number(value) => write!(f, "{}", value),
+            ConfigValue::String(value) => write!(f, "{:?}", value),
+            ConfigValue::Nil {} => write!(f, "nil"),
+        }
+    }
+}
+
+/// How polyfill exports are brought into output files.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PolyfillMode {
+    /// Requires exports from the shared polyfill module.
+    #[default]
+    Require,
+    /// Emits the polyfill definitions at the top of every file that uses them.
+    Inline,
+}
+
+/// How output files require the shared polyfill module.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RequireStyle {
+    /// Slash path relative to the requiring file, such as `require'./__polyfill__'`.
+    #[default]
+    Relative,
+    /// Dotted module path from the output root, such as `require'__polyfill__'`.
+    Dotted,
+    /// Custom expression loading the module. `{path}` is replaced with the relative slash path
+    /// and `{module}` with the dotted module path.
+    Template(String),
+}
+
+/// Source of a polyfill.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum PolyfillSource {
+    /// Git repository that is cloned into the cache directory.
+    Repository(Url),
+    /// Local directory such as a vendored polyfill. `repository` records where it came from.
+    Path {
+        path: PathBuf,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        repository: Option<Url>,
+    },
+}
+
+impl PolyfillSource {
+    /// Loads polyfill cache of the source.
+    pub async fn load(
+        &self,
+        cache_dir: &Path,
+        target_version: &TargetVersion,
+    ) -> Result<PolyfillCache> {
+        match self {
+            PolyfillSource::Repository(url) => {
+                PolyfillCache::new(cache_dir, url, target_version).await
+            }
+            PolyfillSource::Path { path, .. } => {
+                PolyfillCache::from_path(path, target_version).await
+            }
+        }
+    }
+
+    /// Gets git repository url of the source, if any.
+    pub fn repository(&self) -> Option<&Url> {
+        match self {
+            PolyfillSource::Repository(url) => Some(url),
+            PolyfillSource::Path { .. } => None,
+        }
+    }
+
+    /// Gets where the polyfill originally comes from: its repository, or its path if it has none.
+    fn origin(&self) -> String {
+        match self {
+            PolyfillSource::Repository(url)
+            | PolyfillSource::Path {
+                repository: Some(url),
+                ..
+            } => url.to_string(),
+            PolyfillSource::Path { path, .. } => std::path::absolute(path)
+                .unwrap_or_else(|_| path.to_owned())
+                .display()
+                .to_string(),
+        }
+    }
+}
+
+/// Checks that `path` is empty or holds a previously vendored copy of the polyfill from `origin`,
+/// so that vendoring doesn't delete anything else.
+async fn check_vendor_target(path: &Path, origin: &str) -> Result<()> {
+    if !path.exists() || fs::read_dir(path).await?.next_entry().await?.is_none() {
+        return Ok(());
+    }
+    if path.join("polyfill.toml").is_file() {
+        if let Ok(vendored_origin) = fs::read_to_string(path.join(VENDOR_SOURCE_FILE_NAME)).await {
+            if vendored_origin == origin {
+                return Ok(());
+            }
+        }
+    }
+    Err(anyhow!(
+        "Cannot vendor polyfill into {:?} because it is not empty and doesn't hold a vendored copy of {}",
+        path,
+        origin
+    ))
+}
+
+impl fmt::Display for PolyfillSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolyfillSource::Repository(url) => write!(f, "{}", url),
+            PolyfillSource::Path { path, .. } => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[inline]
+fn is_default_require_style(require_style: &RequireStyle) -> bool {
+    *require_style == RequireStyle::default()
+}
+
 /// Polyfill-related manifest.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Polyfill {
     repository: Url,
+    /// Local polyfill directory used instead of cloning `repository`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    path: Option<PathBuf>,
+    /// Additional polyfills layered on top of the base polyfill, in order.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    layers: Vec<PolyfillSource>,
     #[serde(skip_serializing_if = "HashMap::is_empty")]
     #[serde(default)]
     globals: HashMap<String, bool>,
     #[serde(skip_serializing_if = "HashMap::is_empty")]
     #[serde(default)]
-    config: HashMap<String, bool>,
+    config: HashMap<String, ConfigValue>,
+    /// Path of the shared polyfill module, relative to the output root.
     injection_path: PathBuf,
+    #[serde(default)]
+    mode: PolyfillMode,
+    #[serde(skip_serializing_if = "is_default_require_style")]
+    #[serde(default)]
+    require_style: RequireStyle,
+    /// Cache directory of polyfills. Overridden by `--cache-dir` and `DALBIT_CACHE_DIR`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_dir: Option<PathBuf>,
 }
 
 impl Default for Polyfill {
     fn default() -> Self {
         Self {
             repository: Url::from_str(DEFAULT_REPO_URL).unwrap(),
+            path: None,
+            layers: Vec::new(),
             globals: HashMap::new(),
             config: HashMap::new(),
             injection_path: PathBuf::from_str(DEFAULT_INJECTION_PATH).unwrap(),
+            mode: PolyfillMode::default(),
+            require_style: RequireStyle::default(),
+            cache_dir: None,
         }
     }
 }
@@ -69,15 +486,119 @@ impl Polyfill {
     pub fn new(repository: Url, injection_path: PathBuf) -> Self {
         Self {
             repository,
+            path: None,
+            layers: Vec::new(),
             globals: HashMap::new(),
             config: HashMap::new(),
             injection_path,
+            mode: PolyfillMode::default(),
+            require_style: RequireStyle::default(),
+            cache_dir: None,
         }
     }
 
     /// Loads polyfill cache.
-    pub async fn cache(&self) -> Result<PolyfillCache> {
-        PolyfillCache::new(&self.repository).await
+    pub async fn cache(&self, target_version: &TargetVersion) -> Result<PolyfillCache> {
+        self.base_source()
+            .load(&self.cache_dir()?, target_version)
+            .await
+    }
+
+    /// Loads polyfill caches of every layer.
+    pub async fn caches(&self, target_version: &TargetVersion) -> Result<PolyfillLayers> {
+        let cache_dir = self.cache_dir()?;
+        let mut caches = Vec::new();
+        for source in self.sources() {
+            caches.push(source.load(&cache_dir, target_version).await?);
+        }
+        Ok(PolyfillLayers { caches })
+    }
+
+    /// Copies every polyfill layer into `dir` and points the manifest at the copies.
+    ///
+    /// Returns paths of the copied polyfills.
+    pub async fn vendor(
+        &mut self,
+        dir: &Path,
+        target_version: &TargetVersion,
+    ) -> Result<Vec<PathBuf>> {
+        let polyfill_layers = self.caches(target_version).await?;
+        let sources = self.sources();
+        let cache_paths = polyfill_layers
+            .caches()
+            .iter()
+            .map(|cache| std::path::absolute(cache.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut vendored = Vec::new();
+        let mut copies = Vec::new();
+        for (i, (source, cache)) in sources.iter().zip(polyfill_layers.caches()).enumerate() {
+            let vendor_path = if sources.len() == 1 {
+                dir.to_owned()
+            } else {
+                dir.join(i.to_string())
+            };
+            let absolute_vendor_path = std::path::absolute(&vendor_path)?;
+            if cache_paths[i] != absolute_vendor_path {
+                // copying would delete or recurse into a polyfill that is being vendored
+                if let Some(cache_path) = cache_paths.iter().find(|cache_path| {
+                    cache_path.starts_with(&absolute_vendor_path)
+                        || absolute_vendor_path.starts_with(cache_path)
+                }) {
+                    return Err(anyhow!(
+                        "Cannot vendor polyfill {} into {:?} because it overlaps polyfill {:?}",
+                        source,
+                        vendor_path,
+                        cache_path
+                    ));
+                }
+                check_vendor_target(&vendor_path, &source.origin()).await?;
+                copies.push((source, cache.path(), vendor_path.clone()));
+            }
+            vendored.push(vendor_path);
+        }
+
+        for (source, cache_path, vendor_path) in copies {
+            if vendor_path.exists() {
+                fs::remove_dir_all(&vendor_path).await?;
+            }
+            utils::copy_dir(cache_path, &vendor_path, &[".git"]).await?;
+            fs::write(vendor_path.join(VENDOR_SOURCE_FILE_NAME), source.origin()).await?;
+            log::info!("vendored polyfill {} into {:?}", source, vendor_path);
+        }
+
+        self.path = Some(vendored[0].clone());
+        for (layer, vendor_path) in self.layers.iter_mut().zip(vendored.iter().skip(1)) {
+            let repository = match layer {
+                PolyfillSource::Repository(url) => Some(url.to_owned()),
+                PolyfillSource::Path { repository, .. } => repository.to_owned(),
+            };
+            *layer = PolyfillSource::Path {
+                path: vendor_path.to_owned(),
+                repository,
+            };
+        }
+
+        Ok(vendored)
+    }
+
+    /// Gets source of the base polyfill.
+    pub fn base_source(&self) -> PolyfillSource {
+        if let Some(path) = &self.path {
+            PolyfillSource::Path {
+                path: path.to_owned(),
+                repository: Some(self.repository.to_owned()),
+            }
+        } else {
+            PolyfillSource::Repository(self.repository.to_owned())
+        }
+    }
+
+    /// Gets every polyfill source, the base polyfill first.
+    pub fn sources(&self) -> Vec<PolyfillSource> {
+        let mut sources = vec![self.base_source()];
+        sources.extend(self.layers.iter().cloned());
+        sources
     }
 
     #[inline]
@@ -85,13 +606,23 @@ impl Polyfill {
         &self.repository
     }
 
+    #[inline]
+    pub fn path(&self) -> &Option<PathBuf> {
+        &self.path
+    }
+
+    #[inline]
+    pub fn layers(&self) -> &Vec<PolyfillSource> {
+        &self.layers
+    }
+
     #[inline]
     pub fn globals(&self) -> &HashMap<String, bool> {
         &self.globals
     }
 
     #[inline]
-    pub fn config(&self) -> &HashMap<String, bool> {
+    pub fn config(&self) -> &HashMap<String, ConfigValue> {
         &self.config
     }
 
@@ -99,15 +630,88 @@ impl Polyfill {
     pub fn injection_path(&self) -> &PathBuf {
         &self.injection_path
     }
+
+    #[inline]
+    pub fn mode(&self) -> PolyfillMode {
+        self.mode
+    }
+
+    #[inline]
+    pub fn require_style(&self) -> &RequireStyle {
+        &self.require_style
+    }
+
+    /// Gets cache directory path of polyfills, considering the manifest's `cache_dir`.
+    pub fn cache_dir(&self) -> Result<PathBuf> {
+        resolve_cache_dir(self.cache_dir.as_deref())
+    }
+}
+
+/// Globals module used for a specific target version.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum TargetGlobals {
+    /// Module written in the polyfill's `lua_version` and transpiled for the target.
+    Path(PathBuf),
+    /// Module written in its own `lua_version`, used as is instead of being transpiled.
+    Module {
+        path: PathBuf,
+        lua_version: TargetVersion,
+    },
+}
+
+impl TargetGlobals {
+    #[inline]
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            TargetGlobals::Path(path) => path,
+            TargetGlobals::Module { path, .. } => path,
+        }
+    }
 }
 
 /// Polyfill's manifest (`/polyfill.toml` in a polyfill repository)
 #[derive(Debug, Deserialize, Serialize)]
 pub struct PolyfillManifest {
     globals: PathBuf,
+    /// Globals used instead of `globals` for specific target versions.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    target_globals: HashMap<TargetVersion, TargetGlobals>,
     removes: Option<Vec<String>>,
-    config: HashMap<String, bool>,
+    /// Exported builtin libraries whose members are added to the builtin instead of replacing it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extends: Option<Vec<String>>,
+    config: HashMap<String, ConfigValue>,
     lua_version: TargetVersion,
+    /// Version of the polyfill itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    /// Minimum dalbit version required by the polyfill.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_dalbit_version: Option<Version>,
+    /// Target versions supported by the polyfill. Every target is supported when omitted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    targets: Option<Vec<TargetVersion>>,
+}
+
+impl Default for PolyfillManifest {
+    fn default() -> Self {
+        Self {
+            globals: PathBuf::from("globals.luau"),
+            target_globals: HashMap::new(),
+            removes: None,
+            extends: None,
+            config: HashMap::from([("example_flag".to_owned(), ConfigValue::Boolean(false))]),
+            lua_version: TargetVersion::Luau,
+            version: Some("0.1.0".to_owned()),
+            min_dalbit_version: Version::parse(env!("CARGO_PKG_VERSION")).ok(),
+            targets: Some(vec![
+                TargetVersion::Lua51,
+                TargetVersion::Lua52,
+                TargetVersion::Lua53,
+            ]),
+        }
+    }
 }
 
 impl PolyfillManifest {
@@ -120,11 +724,122 @@ impl PolyfillManifest {
         Ok(manifest)
     }
 
+    /// Checks whether the polyfill can be used by this dalbit for the given target version.
+    pub fn check_compatibility(&self, target_version: &TargetVersion) -> Result<()> {
+        if let Some(min_dalbit_version) = &self.min_dalbit_version {
+            let dalbit_version = Version::parse(env!("CARGO_PKG_VERSION"))?;
+            if &dalbit_version < min_dalbit_version {
+                return Err(anyhow!(
+                    "Polyfill requires dalbit {} or newer, but this is dalbit {}",
+                    min_dalbit_version,
+                    dalbit_version
+                ));
+            }
+        }
+        if let Some(targets) = &self.targets {
+            if !targets.contains(target_version) {
+                return Err(anyhow!(
+                    "Polyfill does not support target version `{}` (supported: {})",
+                    target_version,
+                    targets
+                        .iter()
+                        .map(|target| format!("`{}`", target))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
     /// Write polyfill manifest to file.
     pub async fn write(&self, path: impl Into<PathBuf>) -> Result<()> {
         fs::write(path.into(), toml::to_string(self)?).await?;
         Ok(())
     }
+
+    #[inline]
+    pub fn globals(&self) -> &PathBuf {
+        &self.globals
+    }
+
+    #[inline]
+    pub fn target_globals(&self) -> &HashMap<TargetVersion, TargetGlobals> {
+        &self.target_globals
+    }
+
+    /// Gets path of the globals for the given target version.
+    pub fn globals_for(&self, target_version: &TargetVersion) -> &PathBuf {
+        self.target_globals
+            .get(target_version)
+            .map_or(&self.globals, TargetGlobals::path)
+    }
+
+    /// Gets Lua version of the globals for the given target version when they are used as is
+    /// instead of being transpiled.
+    pub fn untranspiled_lua_version_for(
+        &self,
+        target_version: &TargetVersion,
+    ) -> Option<&TargetVersion> {
+        match self.target_globals.get(target_version) {
+            Some(TargetGlobals::Module { lua_version, .. }) => Some(lua_version),
+            _ => None,
+        }
+    }
+
+    /// Gets Lua version the globals for the given target version are parsed with.
+    pub fn lua_version_for(&self, target_version: &TargetVersion) -> &TargetVersion {
+        self.untranspiled_lua_version_for(target_version)
+            .unwrap_or(&self.lua_version)
+    }
+
+    #[inline]
+    pub fn removes(&self) -> &Option<Vec<String>> {
+        &self.removes
+    }
+
+    #[inline]
+    pub fn extends(&self) -> &Option<Vec<String>> {
+        &self.extends
+    }
+
+    #[inline]
+    pub fn config(&self) -> &HashMap<String, ConfigValue> {
+        &self.config
+    }
+
+    #[inline]
+    pub fn lua_version(&self) -> &TargetVersion {
+        &self.lua_version
+    }
+
+    #[inline]
+    pub fn targets(&self) -> &Option<Vec<TargetVersion>> {
+        &self.targets
+    }
+}
+
+/// Changes found while fetching a polyfill repository.
+#[derive(Debug)]
+pub struct FetchSummary {
+    pub old_commit: String,
+    pub new_commit: String,
+    /// Fetched commits, newest first.
+    pub commits: Vec<String>,
+    pub added_exports: Vec<String>,
+    pub removed_exports: Vec<String>,
+}
+
+impl FetchSummary {
+    #[inline]
+    pub fn is_changed(&self) -> bool {
+        self.old_commit != self.new_commit
+    }
+}
+
+#[inline]
+fn short_id(oid: &git2::Oid) -> String {
+    oid.to_string().chars().take(7).collect()
 }
 
 /// Polyfill's globals.
@@ -132,18 +847,23 @@ impl PolyfillManifest {
 pub struct Globals {
     path: PathBuf,
     exports: HashSet<String>,
+    /// Lua version of globals that are used as is instead of being transpiled.
+    untranspiled_lua_version: Option<TargetVersion>,
 }
 
 /// Represents a loaded polyfill cache.
 pub struct PolyfillCache {
-    repository: Repository,
+    repository: Option<Repository>,
     path: PathBuf,
+    target_version: TargetVersion,
     globals: Globals,
     removes: Option<Vec<String>>,
-    config: HashMap<String, bool>,
+    extends: Option<Vec<String>>,
+    config: HashMap<String, ConfigValue>,
+    version: Option<String>,
 }
 
-fn index_path(url: &Url) -> anyhow::Result<PathBuf> {
+fn index_name(url: &Url) -> String {
     let name = match (url.domain(), url.scheme()) {
         (Some(domain), _) => domain,
         (None, "file") => "local",
@@ -152,19 +872,79 @@ fn index_path(url: &Url) -> anyhow::Result<PathBuf> {
 
     let hash = blake3::hash(url.to_string().as_bytes());
     let hash_hex = hex::encode(&hash.as_bytes()[..8]);
-    let ident = format!("{}-{}", name, hash_hex);
+    format!("{}-{}", name, hash_hex)
+}
 
-    let path = cache_dir()?.join(ident);
+fn index_path(cache_dir: &Path, url: &Url) -> PathBuf {
+    let path = cache_dir.join(index_name(url));
 
     log::debug!("index path {:?}", path);
 
-    Ok(path)
+    path
+}
+
+#[inline]
+fn index_lock_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".lock");
+    path.with_file_name(file_name)
+}
+
+/// Opens and locks the given lock file, shared or exclusively.
+fn lock_file(lock_path: &Path, exclusive: bool) -> Result<std::fs::File> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path)
+        .with_context(|| format!("Could not open lock file: {:?}", lock_path))?;
+    if exclusive {
+        file.lock()
+    } else {
+        file.lock_shared()
+    }
+    .with_context(|| format!("Could not lock {:?}", lock_path))?;
+    Ok(file)
+}
+
+/// Locks the whole cache directory against other dalbit processes until the returned file is
+/// dropped. Caches in the directory can't be locked meanwhile.
+fn lock_cache_dir(cache_dir: &Path) -> Result<std::fs::File> {
+    fs_err::create_dir_all(cache_dir)?;
+    lock_file(&cache_dir.join(CACHE_LOCK_FILE_NAME), true)
+        .context("Could not lock polyfill cache directory")
+}
+
+/// Lock of a polyfill cache, released when dropped.
+struct IndexLock {
+    _cache_dir: std::fs::File,
+    _index: std::fs::File,
+}
+
+/// Locks the cache at `path` against other dalbit processes until the returned lock is dropped.
+///
+/// The cache directory is locked shared as well, so caches are never cleaned while in use.
+fn lock_index(path: &Path) -> Result<IndexLock> {
+    let cache_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    fs_err::create_dir_all(cache_dir)?;
+    let cache_dir_lock = lock_file(&cache_dir.join(CACHE_LOCK_FILE_NAME), false)
+        .context("Could not lock polyfill cache directory")?;
+    let index = lock_file(&index_lock_path(path), true)
+        .with_context(|| format!("Could not lock polyfill cache: {:?}", path))?;
+    index.set_modified(SystemTime::now())?;
+    Ok(IndexLock {
+        _cache_dir: cache_dir_lock,
+        _index: index,
+    })
 }
 
 impl PolyfillCache {
     /// Creates a new polyfill from git repository.
-    pub async fn new(url: &Url) -> Result<Self> {
-        let path = index_path(url)?;
+    ///
+    /// Fails if the polyfill does not support `target_version` or requires a newer dalbit.
+    pub async fn new(cache_dir: &Path, url: &Url, target_version: &TargetVersion) -> Result<Self> {
+        let path = index_path(cache_dir, url);
+        let _lock = lock_index(&path)?;
         let repository = match Repository::open(path.as_path()) {
             Ok(repo) => repo,
             Err(_) => {
@@ -176,19 +956,40 @@ impl PolyfillCache {
 
                 fs_err::create_dir_all(path.as_path())?;
                 let auth = GitAuthenticator::new();
-                auth.clone_repo(url, &path.as_path())?
+                auth.clone_repo(url, path.as_path())?
             }
         };
 
         log::info!("repository is ready");
 
+        Self::load(path, Some(repository), target_version)
+            .await
+            .with_context(|| format!("Could not load polyfill `{}`", url))
+    }
+
+    /// Creates a new polyfill from a local directory.
+    pub async fn from_path(path: &Path, target_version: &TargetVersion) -> Result<Self> {
+        Self::load(path.to_owned(), None, target_version)
+            .await
+            .with_context(|| format!("Could not load polyfill {:?}", path))
+    }
+
+    async fn load(
+        path: PathBuf,
+        repository: Option<Repository>,
+        target_version: &TargetVersion,
+    ) -> Result<Self> {
         //let manifest = Manifest::from_file(path.join("polyfill.toml")).await?;
         let manifest_content = fs::read_to_string(path.join("polyfill.toml")).await?;
         let manifest: PolyfillManifest = toml::from_str(&manifest_content)?;
+        manifest
+            .check_compatibility(target_version)
+            .context("Incompatible polyfill")?;
 
-        let globals_path = path.join(&manifest.globals);
+        let globals_path = path.join(manifest.globals_for(target_version));
         log::debug!("globals path {:?}", globals_path);
-        let globals_ast = utils::parse_file(&globals_path, &manifest.lua_version).await?;
+        let globals_ast =
+            utils::parse_file(&globals_path, manifest.lua_version_for(target_version)).await?;
         let exports = utils
             ::get_exports_from_last_stmt(&utils::ParseTarget::FullMoonAst(globals_ast)).await?
             .ok_or_else(||
@@ -196,10 +997,21 @@ impl PolyfillCache {
                     "Invalid polyfill structure. Polyfills' globals must return at least one global in a table."
                 )
             )?;
+        for name in manifest.extends.iter().flatten() {
+            if !exports.contains(name) {
+                return Err(anyhow!(
+                    "Invalid polyfill structure. Extended library `{}` is not exported.",
+                    name
+                ));
+            }
+        }
 
         let globals = Globals {
             path: globals_path,
             exports,
+            untranspiled_lua_version: manifest
+                .untranspiled_lua_version_for(target_version)
+                .cloned(),
         };
 
         log::info!("polyfill ready");
@@ -207,35 +1019,123 @@ impl PolyfillCache {
         Ok(Self {
             path,
             repository,
-            globals: globals,
+            target_version: target_version.clone(),
+            globals,
             removes: manifest.removes,
+            extends: manifest.extends,
             config: manifest.config,
+            version: manifest.version,
         })
     }
 
     /// Fetches and updates polyfill repository using git.
-    pub fn fetch(&self) -> Result<()> {
-        let mut remote = self.repository.find_remote("origin")?;
+    ///
+    /// With `dry_run`, the changes are only reported and the repository is left as is.
+    pub fn fetch(&self, dry_run: bool) -> Result<FetchSummary> {
+        let repository = self.git_repository()?;
+        let _lock = lock_index(&self.path)?;
+        let mut remote = repository.find_remote("origin")?;
         let auth = GitAuthenticator::new();
-        auth.fetch(&self.repository, &mut remote, &["main"], None)
-            .with_context(|| format!("Could not fetch git repository"))?;
+        auth.fetch(repository, &mut remote, &["main"], None)
+            .context("Could not fetch git repository")?;
 
-        let mut options = git2::build::CheckoutBuilder::new();
-        options.force();
+        let old_commit = repository.head()?.peel_to_commit()?;
+        let new_commit = repository.find_reference("FETCH_HEAD")?.peel_to_commit()?;
 
-        let commit = self
-            .repository
-            .find_reference("FETCH_HEAD")?
-            .peel_to_commit()?;
-        self.repository
-            .reset(
-                &commit.into_object(),
-                git2::ResetType::Hard,
-                Some(&mut options),
-            )
-            .with_context(|| format!("Could not reset git repo to fetch_head"))?;
+        let mut commits = Vec::new();
+        let mut revwalk = repository.revwalk()?;
+        revwalk.push(new_commit.id())?;
+        revwalk.hide(old_commit.id())?;
+        for oid in revwalk {
+            let commit = repository.find_commit(oid?)?;
+            commits.push(format!(
+                "{} {}",
+                short_id(&commit.id()),
+                commit.summary().unwrap_or_default()
+            ));
+        }
 
-        Ok(())
+        let new_exports = self.exports_at(&new_commit)?;
+        let mut added_exports: Vec<String> = new_exports
+            .difference(self.globals_exports())
+            .cloned()
+            .collect();
+        added_exports.sort();
+        let mut removed_exports: Vec<String> = self
+            .globals_exports()
+            .difference(&new_exports)
+            .cloned()
+            .collect();
+        removed_exports.sort();
+
+        let summary = FetchSummary {
+            old_commit: old_commit.id().to_string(),
+            new_commit: new_commit.id().to_string(),
+            commits,
+            added_exports,
+            removed_exports,
+        };
+
+        if !dry_run {
+            let mut options = git2::build::CheckoutBuilder::new();
+            options.force();
+
+            repository
+                .reset(
+                    &new_commit.into_object(),
+                    git2::ResetType::Hard,
+                    Some(&mut options),
+                )
+                .context("Could not reset git repo to fetch_head")?;
+        }
+
+        Ok(summary)
+    }
+
+    /// Reads globals' exports of the polyfill at the given commit.
+    fn exports_at(&self, commit: &git2::Commit) -> Result<HashSet<String>> {
+        let tree = commit.tree()?;
+        let read_blob = |path: &Path| -> Result<String> {
+            let blob = tree
+                .get_path(path)?
+                .to_object(self.git_repository()?)?
+                .peel_to_blob()?;
+            Ok(String::from_utf8_lossy(blob.content()).into_owned())
+        };
+
+        let manifest: PolyfillManifest = toml::from_str(&read_blob(Path::new("polyfill.toml"))?)?;
+        let globals = read_blob(manifest.globals_for(&self.target_version))?;
+        let globals_ast = utils::parse_code(
+            &globals,
+            manifest
+                .lua_version_for(&self.target_version)
+                .to_lua_version(),
+        )?;
+
+        Ok(utils::get_exports_from_ast(&globals_ast).unwrap_or_default())
+    }
+
+    /// Gets id of the commit the polyfill repository is checked out at.
+    /// Local polyfills have no commit.
+    pub fn commit_id(&self) -> Result<Option<String>> {
+        if let Some(repository) = &self.repository {
+            let commit = repository.head()?.peel_to_commit()?;
+            Ok(Some(commit.id().to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Whether the polyfill is a git repository that can be fetched.
+    #[inline]
+    pub fn is_git(&self) -> bool {
+        self.repository.is_some()
+    }
+
+    fn git_repository(&self) -> Result<&Repository> {
+        self.repository
+            .as_ref()
+            .ok_or_else(|| anyhow!("Polyfill {:?} is not a git repository", self.path))
     }
 
     #[inline]
@@ -253,13 +1153,170 @@ impl PolyfillCache {
         &self.globals.exports
     }
 
+    /// Gets Lua version of the globals when they are used as is instead of being transpiled.
+    #[inline]
+    pub fn globals_untranspiled_lua_version(&self) -> Option<&TargetVersion> {
+        self.globals.untranspiled_lua_version.as_ref()
+    }
+
     #[inline]
     pub fn removes(&self) -> &Option<Vec<String>> {
         &self.removes
     }
 
     #[inline]
-    pub fn config(&self) -> &HashMap<String, bool> {
+    pub fn extends(&self) -> &Option<Vec<String>> {
+        &self.extends
+    }
+
+    #[inline]
+    pub fn config(&self) -> &HashMap<String, ConfigValue> {
         &self.config
     }
-}
\ No newline at end of file
+
+    #[inline]
+    pub fn version(&self) -> &Option<String> {
+        &self.version
+    }
+}
+
+/// Loaded polyfill caches of every layer. Later layers take precedence over earlier ones.
+pub struct PolyfillLayers {
+    caches: Vec<PolyfillCache>,
+}
+
+impl PolyfillLayers {
+    /// Fetches and updates every polyfill repository using git.
+    /// Local polyfills are skipped and have no summary.
+    pub fn fetch(&self, dry_run: bool) -> Result<Vec<Option<FetchSummary>>> {
+        self.caches
+            .iter()
+            .map(|cache| {
+                if cache.is_git() {
+                    cache.fetch(dry_run).map(Some)
+                } else {
+                    Ok(None)
+                }
+            })
+            .collect()
+    }
+
+    #[inline]
+    pub fn caches(&self) -> &Vec<PolyfillCache> {
+        &self.caches
+    }
+
+    /// Exports of every layer's globals.
+    pub fn globals_exports(&self) -> HashSet<String> {
+        self.caches
+            .iter()
+            .flat_map(|cache| cache.globals_exports().iter().cloned())
+            .collect()
+    }
+
+    /// Globals removed by any layer, in order of appearance.
+    pub fn removes(&self) -> Option<Vec<String>> {
+        let mut removes: Vec<String> = Vec::new();
+        for cache in &self.caches {
+            for name in cache.removes().iter().flatten() {
+                if !removes.contains(name) {
+                    removes.push(name.to_owned());
+                }
+            }
+        }
+        if removes.is_empty() {
+            None
+        } else {
+            Some(removes)
+        }
+    }
+
+    /// Builtin libraries extended by any layer.
+    pub fn extends(&self) -> HashSet<String> {
+        self.caches
+            .iter()
+            .flat_map(|cache| cache.extends().iter().flatten().cloned())
+            .collect()
+    }
+
+    /// Config defaults of every layer, later layers overriding earlier ones.
+    pub fn config(&self) -> HashMap<String, ConfigValue> {
+        let mut config = HashMap::new();
+        for cache in &self.caches {
+            config.extend(
+                cache
+                    .config()
+                    .iter()
+                    .map(|(k, v)| (k.to_owned(), v.to_owned())),
+            );
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_value_nil_is_empty_table() {
+        let config: HashMap<String, ConfigValue> =
+            toml::from_str("flag = true\nfallback = {}").unwrap();
+        assert_eq!(config["flag"], ConfigValue::Boolean(true));
+        assert_eq!(config["fallback"], ConfigValue::Nil {});
+        assert!(toml::from_str::<HashMap<String, ConfigValue>>("fallback = { a = 1 }").is_err());
+    }
+
+    #[test]
+    fn target_globals_declare_lua_version() {
+        let manifest: PolyfillManifest = toml::from_str(
+            r#"
+            globals = "globals.luau"
+            config = {}
+            lua_version = "luau"
+
+            [target_globals]
+            lua52 = "globals52.luau"
+            lua53 = { path = "globals53.lua", lua_version = "lua53" }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            manifest.globals_for(&TargetVersion::Lua52),
+            &PathBuf::from("globals52.luau")
+        );
+        assert_eq!(
+            manifest.lua_version_for(&TargetVersion::Lua52),
+            &TargetVersion::Luau
+        );
+        assert_eq!(
+            manifest.untranspiled_lua_version_for(&TargetVersion::Lua52),
+            None
+        );
+        assert_eq!(
+            manifest.globals_for(&TargetVersion::Lua53),
+            &PathBuf::from("globals53.lua")
+        );
+        assert_eq!(
+            manifest.untranspiled_lua_version_for(&TargetVersion::Lua53),
+            Some(&TargetVersion::Lua53)
+        );
+        assert_eq!(
+            manifest.globals_for(&TargetVersion::Lua51),
+            &PathBuf::from("globals.luau")
+        );
+    }
+
+    #[test]
+    fn config_value_to_lua() {
+        assert_eq!(ConfigValue::Number(20.0).to_lua(), "20");
+        assert_eq!(ConfigValue::Number(0.5).to_lua(), "0.5");
+        assert_eq!(ConfigValue::Number(f64::INFINITY).to_lua(), "(1/0)");
+        assert_eq!(
+            ConfigValue::String("a\"b\\\n\0".to_owned()).to_lua(),
+            "\"a\\\"b\\\\\\n\\000\""
+        );
+        assert_eq!(ConfigValue::Nil {}.to_lua(), "nil");
+    }
+}
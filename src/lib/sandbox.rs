@@ -0,0 +1,236 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::TargetVersion;
+
+/// Globals kept by the `love2d` profile even if the polyfill removes them.
+pub const LOVE2D_ALLOWED_GLOBALS: [&str; 6] =
+    ["io", "os", "load", "loadstring", "require", "package"];
+
+/// Globals removed by the `strict` profile in addition to the polyfill's removes.
+pub const STRICT_DENIED_GLOBALS: [&str; 11] = [
+    "collectgarbage",
+    "debug",
+    "dofile",
+    "getfenv",
+    "io",
+    "load",
+    "loadfile",
+    "loadstring",
+    "module",
+    "os",
+    "setfenv",
+];
+
+/// Identifier of the function creating stubs for removed globals.
+const STUB_IDENTIFIER: &str = "_DALBIT_SANDBOX_STUB";
+
+/// Named set of removed globals.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SandboxProfile {
+    /// Removes the globals the polyfill removes.
+    #[default]
+    Polyfill,
+    /// Removes nothing.
+    None,
+    /// Removes the polyfill's removes except globals Love2D games rely on.
+    Love2d,
+    /// Removes the polyfill's removes and every global that reaches the host or loads code.
+    Strict,
+}
+
+/// Sandbox-related manifest.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Sandbox {
+    #[serde(default)]
+    profile: SandboxProfile,
+    /// Globals kept even if the profile removes them.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    allow: Vec<String>,
+    /// Globals removed in addition to the profile's.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    deny: Vec<String>,
+    /// Replaces removed globals with stubs that raise an error when used, instead of `nil`.
+    #[serde(default)]
+    stubs: bool,
+    /// Removes globals through an `_ENV` sandbox instead of shadowing locals. Lua 5.2 or later.
+    #[serde(default)]
+    env: bool,
+}
+
+impl Sandbox {
+    /// Checks whether the sandbox can be used for the given target version.
+    pub fn check_compatibility(&self, target_version: &TargetVersion) -> Result<()> {
+        if self.env && matches!(target_version, TargetVersion::Lua51 | TargetVersion::Luau) {
+            return Err(anyhow!(
+                "`_ENV` sandbox requires Lua 5.2 or later, but the target version is `{}`",
+                target_version
+            ));
+        }
+        Ok(())
+    }
+
+    /// Resolves globals to remove, given the globals the polyfill removes.
+    pub fn removes(&self, polyfill_removes: Option<Vec<String>>) -> Vec<String> {
+        let mut removes = match self.profile {
+            SandboxProfile::None => Vec::new(),
+            _ => polyfill_removes.unwrap_or_default(),
+        };
+        match self.profile {
+            SandboxProfile::Love2d => {
+                removes.retain(|name| !LOVE2D_ALLOWED_GLOBALS.contains(&name.as_str()))
+            }
+            SandboxProfile::Strict => {
+                for name in STRICT_DENIED_GLOBALS {
+                    if !removes.iter().any(|removed| removed == name) {
+                        removes.push(name.to_owned());
+                    }
+                }
+            }
+            _ => {}
+        }
+        for name in &self.deny {
+            if !removes.contains(name) {
+                removes.push(name.to_owned());
+            }
+        }
+        removes.retain(|name| !self.allow.contains(name));
+        removes
+    }
+
+    /// Generates code removing the given globals from a file.
+    pub fn removes_code(&self, removes: &[String]) -> String {
+        if removes.is_empty() {
+            return String::new();
+        }
+
+        let mut code = String::new();
+        let removed_value = |name: &str| {
+            if self.stubs {
+                format!("{}'{}'", STUB_IDENTIFIER, name)
+            } else {
+                "nil".to_owned()
+            }
+        };
+        if self.stubs {
+            code.push_str(&self.stub_function_code());
+        }
+
+        if self.env {
+            let removed = removes
+                .iter()
+                .map(|name| format!("{}=true", name))
+                .collect::<Vec<_>>()
+                .join(",");
+            code.push_str(&format!(
+                "local _ENV=(function(env)local r={{{}}}return setmetatable({{}},{{__index=function(_,k)if r[k]then return {} end return env[k]end,__newindex=env}})end)(_ENV) ",
+                removed,
+                if self.stubs {
+                    format!("{}(k)", STUB_IDENTIFIER)
+                } else {
+                    "nil".to_owned()
+                }
+            ));
+        } else {
+            for name in removes {
+                code.push_str(&format!("local {}={} ", name, removed_value(name)));
+            }
+        }
+
+        code
+    }
+
+    /// Generates code declaring a table of the removed globals, indexed instead of locals.
+    pub fn removes_table_code(&self, removes: &[String], identifier: &str) -> String {
+        let mut code = String::new();
+        let mut fields = String::new();
+        if self.stubs {
+            code.push_str(&self.stub_function_code());
+            fields = removes
+                .iter()
+                .map(|name| format!("{}={}'{}'", name, STUB_IDENTIFIER, name))
+                .collect::<Vec<_>>()
+                .join(",");
+        }
+        code.push_str(&format!("local {}={{{}}} ", identifier, fields));
+        code
+    }
+
+    fn stub_function_code(&self) -> String {
+        format!(
+            "local function {}(n)local function e()error('`'..n..'` is not available in this sandbox',2)end return setmetatable({{}},{{__index=e,__newindex=e,__call=e}})end ",
+            STUB_IDENTIFIER
+        )
+    }
+
+    #[inline]
+    pub fn profile(&self) -> SandboxProfile {
+        self.profile
+    }
+
+    #[inline]
+    pub fn allow(&self) -> &Vec<String> {
+        &self.allow
+    }
+
+    #[inline]
+    pub fn deny(&self) -> &Vec<String> {
+        &self.deny
+    }
+
+    #[inline]
+    pub fn stubs(&self) -> bool {
+        self.stubs
+    }
+
+    #[inline]
+    pub fn env(&self) -> bool {
+        self.env
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn removes(sandbox: &str) -> Vec<String> {
+        let sandbox: Sandbox = toml::from_str(sandbox).unwrap();
+        sandbox.removes(Some(vec!["io".to_owned(), "module".to_owned()]))
+    }
+
+    #[test]
+    fn polyfill_profile_keeps_polyfill_removes() {
+        assert_eq!(removes(""), ["io", "module"]);
+        assert_eq!(removes("profile = \"none\""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn love2d_profile_allows_love2d_globals() {
+        assert_eq!(removes("profile = \"love2d\""), ["module"]);
+    }
+
+    #[test]
+    fn strict_profile_denies_host_globals_once() {
+        let removes = removes("profile = \"strict\"");
+        assert_eq!(removes[..2], ["io", "module"]);
+        assert_eq!(removes.len(), STRICT_DENIED_GLOBALS.len());
+        for name in STRICT_DENIED_GLOBALS {
+            assert!(removes.iter().any(|removed| removed == name));
+        }
+    }
+
+    #[test]
+    fn allow_and_deny_adjust_profile() {
+        assert_eq!(
+            removes("allow = [\"io\"]\ndeny = [\"module\", \"os\"]"),
+            ["module", "os"]
+        );
+        assert_eq!(
+            removes("profile = \"none\"\ndeny = [\"os\"]\nallow = [\"os\"]"),
+            Vec::<String>::new()
+        );
+    }
+}
@@ -1,16 +1,24 @@
-use std::{ffi::OsStr, path::PathBuf, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{self, Path, PathBuf},
+};
 
-use anyhow::{anyhow, Result};
-use async_walkdir::WalkDir;
+use anyhow::{anyhow, Context, Result};
 use darklua_core::{
-    rules::{self, bundle::BundleRequireMode},
-    BundleConfiguration, Configuration, GeneratorParameters, Options, Resources,
+    rules::{self, bundle::BundleRequireMode, Rule, RuleProperties},
+    BundleConfiguration, Configuration, GeneratorParameters, Options, Resources, WorkerTree,
 };
-use futures_lite::stream::StreamExt;
 use indexmap::IndexMap;
 use tokio::fs;
 
-use crate::{injector::Injector, manifest::Manifest, modifiers::Modifier, utils};
+use crate::{
+    directives::FileDirectives,
+    injector::Injector,
+    manifest::Manifest,
+    modifiers::{self, Modifier, ModifierInfo, ModifierPhase, ModifierRegistry, VisitorMutWrapper},
+    polyfill::{self, ConfigValue, PolyfillLayers, PolyfillMode},
+    utils, TargetVersion,
+};
 
 pub const DALBIT_GLOBAL_IDENTIFIER_PREFIX: &str = "DALBIT_";
 
@@ -39,75 +47,321 @@ pub const DEFAULT_MINIFYING_MODIFIERS: [&str; 11] = [
     "remove_empty_do",
 ];
 
-async fn private_process(
+/// Gets the order of the default modifier at the given index of its list.
+#[inline]
+fn default_order(index: usize) -> u32 {
+    (index as u32 + 1) * 10
+}
+
+/// Gets names of the modifiers enabled by the manifest with their phases, in pipeline order.
+///
+/// Default modifiers are ordered 10, 20, 30... in their phase, and modifiers without an order
+/// run after the ordered ones of their phase, in manifest order.
+fn pipeline<'a>(
+    manifest: &'a Manifest,
+    registry: &ModifierRegistry,
+) -> Result<Vec<(ModifierPhase, &'a str)>> {
+    let mut transpiling_modifiers = IndexMap::new();
+    for (index, name) in DEFAULT_LUAU_TO_LUA_MODIFIERS.into_iter().enumerate() {
+        transpiling_modifiers.insert(name, (ModifierPhase::Lower, Some(default_order(index))));
+    }
+    if manifest.minify {
+        for (index, name) in DEFAULT_MINIFYING_MODIFIERS.into_iter().enumerate() {
+            transpiling_modifiers.insert(name, (ModifierPhase::Minify, Some(default_order(index))));
+        }
+    }
+    for (name, config) in manifest.modifiers() {
+        let name = name.as_str();
+        log::debug!("inserted modifier name: {}", name);
+        if !config.is_enabled() {
+            transpiling_modifiers.shift_remove(name);
+            continue;
+        }
+        let (phase, order) = transpiling_modifiers.get(name).copied().unwrap_or_else(|| {
+            let phase = registry.info(name).and_then(ModifierInfo::phase);
+            (phase.unwrap_or(ModifierPhase::Lower), None)
+        });
+        let context = || format!("Invalid modifier `{}` in manifest", name);
+        transpiling_modifiers.insert(
+            name,
+            (
+                config.phase().with_context(context)?.unwrap_or(phase),
+                config.order().with_context(context)?.or(order),
+            ),
+        );
+    }
+
+    let mut modifiers: Vec<_> = transpiling_modifiers.into_iter().collect();
+    modifiers.sort_by_key(|(_, (phase, order))| (*phase, order.is_none(), *order));
+    Ok(modifiers
+        .into_iter()
+        .map(|(name, (phase, _))| (phase, name))
+        .collect())
+}
+
+/// Collects directives of the lua files in `input`, skipping files without any.
+async fn collect_directives(input: &PathBuf) -> Result<Vec<(PathBuf, FileDirectives)>> {
+    let mut files = Vec::new();
+    for path in utils::collect_lua_files(input).await? {
+        let directives = FileDirectives::from_file(&path).await?;
+        if !directives.is_empty() {
+            files.push((path, directives));
+        }
+    }
+    Ok(files)
+}
+
+/// Gets the path a file in the manifest's input is transpiled to.
+fn output_file_path(manifest: &Manifest, file: &Path) -> Result<PathBuf> {
+    let input = manifest.input();
+    let mut path = if input.is_dir() {
+        manifest.output().join(file.strip_prefix(input)?)
+    } else {
+        manifest.output().to_owned()
+    };
+    if let Some(extension) = manifest.file_extension() {
+        path.set_extension(extension);
+    }
+    Ok(path::absolute(path)?)
+}
+
+/// Gets the path darklua writes a file of `input` to, resolved like darklua does before writing.
+fn darklua_output_path(input: &Path, output: &Path, file: &Path) -> Result<PathBuf> {
+    if input.is_dir() {
+        return Ok(output.join(file.strip_prefix(input)?));
+    }
+    if !output.is_dir() && (output.is_file() || output.extension().is_some()) {
+        return Ok(output.to_owned());
+    }
+    let file_name = input
+        .file_name()
+        .ok_or_else(|| anyhow!("Failed to get file name of {:?}", input))?;
+    Ok(output.join(file_name))
+}
+
+/// Transpiles `input` into `output` and returns the created files.
+///
+/// Only the files written from `input` are modified, so files already in `output` such as
+/// outputs of a previous build are left as they are. Created files at `exclude` aren't modified
+/// either.
+///
+/// Additional darklua rules run on every file before the manifest's modifiers, and additional
+/// full_moon visitors run on every file after them.
+pub(crate) async fn private_process(
     manifest: &Manifest,
+    registry: &ModifierRegistry,
     input: &PathBuf,
     output: &PathBuf,
     additional_modifiers: Option<&mut Vec<Modifier>>,
     bundle: bool,
+    exclude: &[PathBuf],
 ) -> Result<Vec<PathBuf>> {
-    let resources = Resources::from_file_system();
+    let mut created_files = Vec::new();
+    for file in utils::collect_lua_files(input).await? {
+        let created_file = darklua_output_path(input, output, &file)?;
+        if !exclude.contains(&path::absolute(&created_file)?) {
+            created_files.push(created_file);
+        }
+    }
 
-    let mut modifiers = Vec::new();
+    let mut additional_rules = Vec::new();
+    let mut additional_visitors = Vec::new();
     if let Some(additional_modifiers) = additional_modifiers {
-        modifiers.append(additional_modifiers);
-    }
-    {
-        let mut transpiling_modifiers = IndexMap::new();
-        for name in DEFAULT_LUAU_TO_LUA_MODIFIERS {
-            transpiling_modifiers.insert(name, true);
-        }
-        for (name, enabled) in manifest.modifiers() {
-            let name = name.as_str();
-            log::debug!("inserted modifier name: {}", name);
-            transpiling_modifiers.insert(name, *enabled);
+        for modifier in additional_modifiers.drain(..) {
+            match modifier {
+                Modifier::DarkluaRule(rule) => additional_rules.push(rule),
+                Modifier::FullMoonVisitor(visitor) => additional_visitors.push(visitor),
+            }
         }
-        for (name, enabled) in transpiling_modifiers {
-            let modifier = Modifier::from_str(name)?;
-            if enabled {
-                modifiers.push(modifier);
+    }
+
+    // the first pass copies or bundles the input into the output, and the manifest's modifiers
+    // then run on the copies in place
+    run_darklua(input, output, additional_rules, bundle)?;
+
+    // files overriding the manifest are transpiled with their own manifest
+    let mut manifest_files = created_files.clone();
+    if input.is_dir() {
+        for (path, directives) in collect_directives(input).await? {
+            if directives.overrides_manifest() {
+                let file_output = darklua_output_path(input, output, &path)?;
+                if !manifest_files.contains(&file_output) {
+                    continue;
+                }
+                manifest_files.retain(|file| *file != file_output);
+                run_modifiers(
+                    &directives.apply(manifest, registry)?,
+                    registry,
+                    &[file_output],
+                )
+                .await?;
             }
         }
+        run_modifiers(manifest, registry, &manifest_files).await?;
+    } else {
+        let directives = FileDirectives::from_file(input).await?;
+        run_modifiers(
+            &directives.apply(manifest, registry)?,
+            registry,
+            &manifest_files,
+        )
+        .await?;
     }
-    if manifest.minify {
-        for name in DEFAULT_MINIFYING_MODIFIERS {
-            modifiers.push(Modifier::from_str(name)?);
+
+    run_visitors(
+        &created_files,
+        manifest.target_version(),
+        &mut additional_visitors,
+    )
+    .await?;
+
+    if let Some(extension) = manifest.file_extension() {
+        for path in &mut created_files {
+            let old_path = path.clone();
+            path.set_extension(extension);
+            fs::rename(old_path, path).await?;
         }
     }
 
-    let (rules, mut fullmoon_visitors) = modifiers.into_iter().fold(
-        (Vec::new(), Vec::new()),
-        |(mut rules, mut fullmoon_visitors), modifier| {
-            match modifier {
-                Modifier::DarkluaRule(darklua_rule) => rules.push(darklua_rule),
-                Modifier::FullMoonVisitor(fullmoon_visitor) => {
-                    fullmoon_visitors.push(fullmoon_visitor);
-                }
+    Ok(created_files)
+}
+
+/// Runs the modifiers enabled by the manifest on the given files in place.
+async fn run_modifiers(
+    manifest: &Manifest,
+    registry: &ModifierRegistry,
+    files: &[PathBuf],
+) -> Result<()> {
+    let mut modifiers = Vec::new();
+    for (phase, name) in pipeline(manifest, registry)? {
+        registry.check_target(name, manifest.target_version())?;
+        let properties = match manifest.modifiers().get(name) {
+            Some(config) => config.properties()?,
+            None => RuleProperties::new(),
+        };
+        modifiers.push((phase, registry.create(name, properties)?));
+    }
+
+    // consecutive modifiers of the same kind run in a single pass
+    let mut stages = Vec::new();
+    for (phase, modifier) in modifiers {
+        match (stages.last_mut(), modifier) {
+            (Some(Stage::Darklua(_, rules)), Modifier::DarkluaRule(rule)) => rules.push(rule),
+            (Some(Stage::FullMoon(_, visitors)), Modifier::FullMoonVisitor(visitor)) => {
+                visitors.push(visitor)
             }
-            (rules, fullmoon_visitors)
-        },
+            (_, Modifier::DarkluaRule(rule)) => stages.push(Stage::Darklua(phase, vec![rule])),
+            (_, Modifier::FullMoonVisitor(visitor)) => {
+                stages.push(Stage::FullMoon(phase, vec![visitor]))
+            }
+        }
+    }
+
+    // code is Luau until every darklua rule up to the `lower` phase has run
+    let last_lowering_stage = stages.iter().rposition(
+        |stage| matches!(stage, Stage::Darklua(phase, _) if *phase <= ModifierPhase::Lower),
     );
+    for (index, stage) in stages.into_iter().enumerate() {
+        match stage {
+            Stage::Darklua(_, rules) => run_darklua_in_place(files, rules)?,
+            Stage::FullMoon(phase, mut visitors) => {
+                let target_version = if phase <= ModifierPhase::Lower
+                    || last_lowering_stage.is_some_and(|last| index < last)
+                {
+                    &TargetVersion::Luau
+                } else {
+                    manifest.target_version()
+                };
+                run_visitors(files, target_version, &mut visitors).await?;
+            }
+        }
+    }
 
-    let mut options = Options::new(input).with_configuration({
-        // let mut config: Configuration = if bundle {
-        //     toml::from_str("bundle = { require_mode = 'path' }").unwrap()
-        // } else {
-        //     Configuration::empty()
-        // };
-        let mut config = Configuration::empty();
-        if bundle {
-            config = config
-                .with_bundle_configuration(BundleConfiguration::new(BundleRequireMode::default()));
+    Ok(())
+}
+
+/// Runs full_moon visitors on the given files in place.
+async fn run_visitors(
+    files: &[PathBuf],
+    target_version: &TargetVersion,
+    visitors: &mut [Box<dyn VisitorMutWrapper>],
+) -> Result<()> {
+    if visitors.is_empty() {
+        return Ok(());
+    }
+    for path in files {
+        let mut ast = utils::parse_file(path, target_version).await?;
+        for visitor in visitors.iter_mut() {
+            ast = visitor.visit_ast_boxed(ast);
         }
-        config = config.with_generator(GeneratorParameters::RetainLines);
+        fs::write(path, ast.to_string()).await?;
+    }
+    Ok(())
+}
 
-        rules
-            .into_iter()
-            .fold(config, |config, rule| config.with_rule(rule))
-    });
-    options = options.with_output(&output);
+/// Modifiers run in a single pass over the files, with the phase of their first modifier.
+enum Stage {
+    Darklua(ModifierPhase, Vec<Box<dyn Rule>>),
+    FullMoon(ModifierPhase, Vec<Box<dyn VisitorMutWrapper>>),
+}
+
+/// Creates the darklua configuration running the given rules.
+fn darklua_configuration(rules: Vec<Box<dyn Rule>>, bundle: bool) -> Configuration {
+    // let mut config: Configuration = if bundle {
+    //     toml::from_str("bundle = { require_mode = 'path' }").unwrap()
+    // } else {
+    //     Configuration::empty()
+    // };
+    let mut config = Configuration::empty();
+    if bundle {
+        config = config
+            .with_bundle_configuration(BundleConfiguration::new(BundleRequireMode::default()));
+    }
+    config = config.with_generator(GeneratorParameters::RetainLines);
+
+    rules
+        .into_iter()
+        .fold(config, |config, rule| config.with_rule(rule))
+}
+
+/// Processes files with darklua rules.
+fn run_darklua(
+    input: &PathBuf,
+    output: &PathBuf,
+    rules: Vec<Box<dyn Rule>>,
+    bundle: bool,
+) -> Result<()> {
+    let resources = Resources::from_file_system();
+
+    let options = Options::new(input)
+        .with_configuration(darklua_configuration(rules, bundle))
+        .with_output(output);
     let result = darklua_core::process(&resources, options).map_err(|e| anyhow!(e))?;
 
+    check_darklua_result(&result)
+}
+
+/// Processes the given files in place with darklua rules.
+fn run_darklua_in_place(files: &[PathBuf], rules: Vec<Box<dyn Rule>>) -> Result<()> {
+    let Some(first_file) = files.first() else {
+        return Ok(());
+    };
+    let resources = Resources::from_file_system();
+
+    let mut result = WorkerTree::default();
+    for file in files {
+        result.add_source(file, None);
+    }
+    // the input of the options is only used to collect work, which is already added
+    let options = Options::new(first_file).with_configuration(darklua_configuration(rules, false));
+    result
+        .process(&resources, options)
+        .map_err(|e| anyhow!(e))?;
+
+    check_darklua_result(&result)
+}
+
+fn check_darklua_result(result: &WorkerTree) -> Result<()> {
     let success_count = result.success_count();
     let errors = result.collect_errors();
     let error_count = errors.len();
@@ -126,137 +380,470 @@ async fn private_process(
         return Err(anyhow!("darklua process was not successful"));
     }
 
-    let mut created_files: Vec<PathBuf> = if output.is_dir() {
-        let mut created_files = Vec::new();
-        let mut entries = WalkDir::new(output);
-        while let Some(entry) = entries.next().await {
-            let path = entry?.path();
-            if !matches!(
-                path.extension().and_then(OsStr::to_str),
-                Some("lua") | Some("luau")
-            ) {
+    Ok(())
+}
+
+/// Checks that every global required by the enabled modifiers is available at runtime.
+///
+/// Polyfill helpers must be exported by the polyfill and enabled, while other globals may also
+/// be Lua builtins as long as the polyfill doesn't remove them.
+fn check_required_globals(
+    manifest: &Manifest,
+    registry: &ModifierRegistry,
+    polyfill_layers: &PolyfillLayers,
+) -> Result<()> {
+    let exports = polyfill_layers.globals_exports();
+    let removes = manifest.sandbox().removes(polyfill_layers.removes());
+    let globals = manifest.polyfill().globals();
+
+    for (_, name) in pipeline(manifest, registry)? {
+        let Some(info) = registry.info(name) else {
+            continue;
+        };
+        for global in info.required_globals() {
+            let is_helper = global.starts_with(modifiers::POLYFILL_HELPER_PREFIX);
+            let is_disabled = globals.get(global) == Some(&false);
+            if is_helper && is_disabled {
+                return Err(anyhow!(
+                    "Modifier `{}` requires global `{}`, but it is disabled in `[polyfill.globals]`",
+                    name,
+                    global
+                ));
+            }
+            // a builtin disabled in the polyfill is still available unless the sandbox removes it
+            if exports.contains(global) && !is_disabled {
                 continue;
             }
-            created_files.push(path);
+            if is_helper {
+                return Err(anyhow!(
+                    "Modifier `{}` requires global `{}`, but the polyfill doesn't export it",
+                    name,
+                    global
+                ));
+            }
+            if removes.iter().any(|removed| removed == global) {
+                return Err(anyhow!(
+                    "Modifier `{}` requires global `{}`, but the sandbox removes it",
+                    name,
+                    global
+                ));
+            }
         }
-        created_files
+    }
+    Ok(())
+}
+
+/// Gets the output directory itself, or the parent of a single output file.
+///
+/// The output is resolved like darklua does, since it may not exist before the first build.
+fn output_root(manifest: &Manifest) -> &Path {
+    let output = manifest.output();
+    if output.is_dir()
+        || manifest.input().is_dir()
+        || (!output.is_file() && output.extension().is_none())
+    {
+        output.as_path()
     } else {
-        vec![output.clone()]
-    };
+        output.parent().unwrap_or_else(|| Path::new(""))
+    }
+}
 
-    let extension = manifest.file_extension();
-    if fullmoon_visitors.is_empty() {
-        if let Some(extension) = extension {
-            for path in &mut created_files {
-                let old_path = path.clone();
-                path.set_extension(extension);
-                fs::rename(old_path, path).await?;
-            }
+/// Resolves the absolute path of the shared polyfill module.
+///
+/// The module is placed relative to the output root (the output directory itself, or the parent
+/// of a single output file) unless `injection_path` is absolute.
+fn polyfill_module_path(manifest: &Manifest, extension: &str) -> Result<PathBuf> {
+    let module_path = output_root(manifest)
+        .join(manifest.polyfill().injection_path())
+        .with_extension(extension);
+
+    Ok(path::absolute(module_path)?)
+}
+
+/// Resolves the global identifier and value of every polyfill config, preferring the manifest's
+/// values.
+fn resolve_polyfill_config<'a>(
+    manifest: &'a Manifest,
+    config: &'a HashMap<String, ConfigValue>,
+) -> Result<Vec<(String, &'a ConfigValue)>> {
+    for key in manifest.polyfill().config().keys() {
+        if !config.contains_key(key) {
+            return Err(anyhow!("Invalid config `{}`", key));
         }
-    } else {
-        for path in &mut created_files {
-            let mut ast = utils::parse_file(path, manifest.target_version()).await?;
+    }
 
-            for visitor in &mut fullmoon_visitors {
-                ast = visitor.visit_ast_boxed(ast);
+    let mut values = Vec::new();
+    for (key, default) in config {
+        let value = if let Some(val) = manifest.polyfill().config().get(key) {
+            if !val.is_compatible_with(default) {
+                return Err(anyhow!(
+                    "Invalid config `{}`: expected {}, got {}",
+                    key,
+                    default.type_name(),
+                    val.type_name()
+                ));
             }
+            val
+        } else {
+            default
+        };
+        let mut identifier = DALBIT_GLOBAL_IDENTIFIER_PREFIX.to_string();
+        identifier.push_str(key);
+        values.push((identifier, value));
+    }
+    Ok(values)
+}
 
-            if let Some(extension) = extension {
-                let old_path = path.clone();
-                path.set_extension(extension);
-                let new_path = path.to_owned();
-                if new_path != old_path && old_path.exists() {
-                    fs::remove_file(old_path).await?;
-                }
-            }
+/// Creates `InjectGlobalValue` rules for polyfill config values, preferring the manifest's values.
+fn polyfill_config_modifiers(
+    manifest: &Manifest,
+    config: &HashMap<String, ConfigValue>,
+) -> Result<Vec<Modifier>> {
+    let mut modifiers: Vec<Modifier> = Vec::new();
+    for (identifier, value) in resolve_polyfill_config(manifest, config)? {
+        let inject_global_value = match value {
+            ConfigValue::Boolean(value) => rules::InjectGlobalValue::boolean(identifier, *value),
+            ConfigValue::Number(value) => rules::InjectGlobalValue::number(identifier, *value),
+            ConfigValue::String(value) => rules::InjectGlobalValue::string(identifier, value),
+            ConfigValue::Nil {} => rules::InjectGlobalValue::nil(identifier),
+        };
+        modifiers.push(Modifier::DarkluaRule(Box::new(inject_global_value)));
+    }
+    Ok(modifiers)
+}
 
-            fs::write(path, ast.to_string()).await?;
-        }
+/// Defines polyfill config values as locals on a single line, for globals that aren't transpiled.
+fn polyfill_config_definitions(
+    manifest: &Manifest,
+    config: &HashMap<String, ConfigValue>,
+) -> Result<String> {
+    let mut values = resolve_polyfill_config(manifest, config)?;
+    values.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(values
+        .into_iter()
+        .map(|(identifier, value)| format!("local {}={} ", identifier, value.to_lua()))
+        .collect())
+}
+
+/// Writes polyfill globals for the manifest's target to `output`.
+///
+/// Globals with an untranspiled Lua version are copied with their config values defined on the
+/// first line, so they never go through the Luau lowering.
+pub(crate) async fn transpile_polyfill_globals(
+    manifest: &Manifest,
+    registry: &ModifierRegistry,
+    globals_path: &PathBuf,
+    untranspiled_lua_version: Option<&TargetVersion>,
+    config: &HashMap<String, ConfigValue>,
+    output: &PathBuf,
+) -> Result<()> {
+    let Some(lua_version) = untranspiled_lua_version else {
+        private_process(
+            manifest,
+            registry,
+            globals_path,
+            output,
+            Some(&mut polyfill_config_modifiers(manifest, config)?),
+            true,
+            &[],
+        )
+        .await?;
+        return Ok(());
+    };
+
+    let source = fs::read_to_string(globals_path).await?;
+    utils::parse_code(&source, lua_version.to_lua_version()).with_context(|| {
+        format!(
+            "Could not parse globals {:?} as `{}`",
+            globals_path, lua_version
+        )
+    })?;
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).await?;
     }
+    fs::write(
+        output,
+        polyfill_config_definitions(manifest, config)? + &source,
+    )
+    .await?;
+    Ok(())
+}
 
-    Ok(created_files)
+/// Transpiles the globals of every polyfill layer into a single module at `module_path`.
+///
+/// With multiple layers, each layer is evaluated in order and its exports are merged into one
+/// table, so later layers override earlier exports with the same name.
+///
+/// Members of the `extends` exports are added to the builtin libraries once the module is loaded.
+async fn transpile_polyfill_module(
+    manifest: &Manifest,
+    registry: &ModifierRegistry,
+    polyfill_layers: &PolyfillLayers,
+    config: &HashMap<String, ConfigValue>,
+    extends: &HashSet<String>,
+    module_path: &PathBuf,
+) -> Result<()> {
+    if let [cache] = polyfill_layers.caches().as_slice() {
+        transpile_polyfill_globals(
+            manifest,
+            registry,
+            cache.globals_path(),
+            cache.globals_untranspiled_lua_version(),
+            config,
+            module_path,
+        )
+        .await?;
+    } else {
+        transpile_polyfill_layers(manifest, registry, polyfill_layers, config, module_path).await?;
+    }
+
+    if !extends.is_empty() {
+        let mut extends: Vec<&String> = extends.iter().collect();
+        extends.sort();
+        let merges: String = extends
+            .into_iter()
+            .map(|lib| format!("for k,v in next,exports.{} do {}[k]=v end\n", lib, lib))
+            .collect();
+        let source = fs::read_to_string(module_path).await?;
+        let module = format!(
+            "local exports=(function()\n{}\nend)()\n{}return exports\n",
+            source, merges
+        );
+        fs::write(module_path, module).await?;
+    }
+
+    Ok(())
+}
+
+async fn transpile_polyfill_layers(
+    manifest: &Manifest,
+    registry: &ModifierRegistry,
+    polyfill_layers: &PolyfillLayers,
+    config: &HashMap<String, ConfigValue>,
+    module_path: &PathBuf,
+) -> Result<()> {
+    let mut layer_sources = Vec::new();
+    for (i, cache) in polyfill_layers.caches().iter().enumerate() {
+        let mut layer_path = module_path.clone();
+        layer_path.set_extension(format!(
+            "layer{}.{}",
+            i,
+            module_path
+                .extension()
+                .map(|ext| ext.to_string_lossy())
+                .unwrap_or_default()
+        ));
+        transpile_polyfill_globals(
+            manifest,
+            registry,
+            cache.globals_path(),
+            cache.globals_untranspiled_lua_version(),
+            config,
+            &layer_path,
+        )
+        .await?;
+        layer_sources.push(fs::read_to_string(&layer_path).await?);
+        fs::remove_file(&layer_path).await?;
+    }
+
+    let layers = layer_sources
+        .iter()
+        .map(|source| format!("(function()\n{}\nend)()", source))
+        .collect::<Vec<_>>()
+        .join(",");
+    let module = format!(
+        "local layers={{{}}}\nlocal exports={{}}\nfor i=1,#layers do for k,v in next,layers[i] do exports[k]=v end end\nreturn exports\n",
+        layers
+    );
+    fs::write(module_path, module).await?;
+
+    Ok(())
 }
 
+/// Transpiles the manifest's input.
+///
+/// Polyfill caches are recorded as referenced by the manifest's `path`, so manifests that weren't
+/// loaded with [`Manifest::from_file`] should set it to keep their caches from being cleaned.
 pub async fn process(
     manifest: Manifest,
     additional_modifiers: Option<&mut Vec<Modifier>>,
 ) -> Result<()> {
+    process_with_registry(manifest, &ModifierRegistry::default(), additional_modifiers).await
+}
+
+/// Transpiles the manifest's input, resolving the manifest's modifiers from the given registry.
+pub async fn process_with_registry(
+    manifest: Manifest,
+    registry: &ModifierRegistry,
+    additional_modifiers: Option<&mut Vec<Modifier>>,
+) -> Result<()> {
+    let polyfill = manifest.polyfill();
+    if let Some(manifest_path) = manifest.path() {
+        polyfill::add_references(manifest_path, polyfill).await?;
+    }
+    let polyfill_layers = polyfill.caches(manifest.target_version()).await?;
+    let polyfill_config = polyfill_layers.config();
+    manifest
+        .sandbox()
+        .check_compatibility(manifest.target_version())?;
+    check_required_globals(&manifest, registry, &polyfill_layers)?;
+
+    let input_files = utils::collect_lua_files(manifest.input()).await?;
+    let Some(first_input) = input_files.first() else {
+        return Ok(());
+    };
+    let extension = if let Some(extension) = manifest.file_extension() {
+        extension.to_owned()
+    } else {
+        first_input
+            .extension()
+            .ok_or_else(|| anyhow!("Failed to get extension from input file."))?
+            .to_string_lossy()
+            .into_owned()
+    };
+    let module_path = polyfill_module_path(&manifest, &extension)?;
+    log::debug!("polyfill module path: {:?}", module_path);
+
+    // a polyfill module left over from a previous build must not be transpiled again
     let output_files = private_process(
         &manifest,
+        registry,
         manifest.input(),
         manifest.output(),
         additional_modifiers,
         manifest.bundle,
+        std::slice::from_ref(&module_path),
     )
     .await?;
-    let polyfill = manifest.polyfill();
-    let polyfill_cache = polyfill.cache().await?;
-    let polyfill_config = polyfill_cache.config();
+    let output_files: Vec<PathBuf> = output_files
+        .into_iter()
+        .filter(|path| {
+            path::absolute(path)
+                .map(|path| path != module_path)
+                .unwrap_or(true)
+        })
+        .collect();
 
-    // needed additional modifiers: inject_global_value
-    let mut additional_modifiers: Vec<Modifier> = Vec::new();
-    for (key, value) in polyfill_config {
-        let value = if let Some(val) = polyfill.config().get(key) {
-            val
-        } else {
-            value
-        };
-        let mut identifier = DALBIT_GLOBAL_IDENTIFIER_PREFIX.to_string();
-        identifier.push_str(key);
-        let inject_global_value = rules::InjectGlobalValue::boolean(identifier, *value);
-        additional_modifiers.push(Modifier::DarkluaRule(Box::new(inject_global_value)));
-    }
-
-    if let Some(first_output) = output_files.first() {
-        log::debug!("first output found!");
-        let extension = if let Some(extension) = manifest.file_extension() {
-            extension.to_owned()
-        } else {
-            first_output
-                .extension()
-                .ok_or_else(|| anyhow!("Failed to get extension from output file."))?
-                .to_string_lossy()
-                .into_owned()
-        };
-        // TODO: share polyfill
-        if let Some(module_path) = first_output.parent().map(|parent| {
-            parent
-                .join(polyfill.injection_path())
-                .with_extension(extension)
-        }) {
-            let _ = private_process(
-                &manifest,
-                polyfill_cache.globals_path(),
-                &module_path,
-                Some(&mut additional_modifiers),
-                true,
-            )
-            .await?;
-
-            let mut exports = polyfill_cache.globals_exports().to_owned();
-            for (key, value) in polyfill.globals() {
-                if exports.contains(key) {
-                    if !value {
-                        exports.remove(key);
-                    }
-                } else {
-                    return Err(anyhow!("Invalid global `{}`", key));
+    if !output_files.is_empty() {
+        let mut exports = polyfill_layers.globals_exports();
+        for (key, value) in polyfill.globals() {
+            if exports.contains(key) {
+                if !value {
+                    exports.remove(key);
                 }
+            } else {
+                return Err(anyhow!("Invalid global `{}`", key));
             }
+        }
+        let extends: HashSet<String> = polyfill_layers
+            .extends()
+            .into_iter()
+            .filter(|lib| exports.contains(lib))
+            .collect();
 
-            log::info!("[injector] exports to be injected: {:?}", exports);
+        transpile_polyfill_module(
+            &manifest,
+            registry,
+            &polyfill_layers,
+            &polyfill_config,
+            &extends,
+            &module_path,
+        )
+        .await?;
 
-            let injector = Injector::new(
-                module_path,
-                exports,
-                manifest.target_version().to_lua_version(),
-                polyfill_cache.removes().to_owned(),
-            );
+        log::info!("[injector] exports to be injected: {:?}", exports);
 
-            for source_path in &output_files {
-                injector.inject(source_path).await?;
-            }
+        let mut injector = Injector::new(
+            module_path.clone(),
+            exports,
+            manifest.target_version().to_lua_version(),
+            Some(manifest.sandbox().removes(polyfill_layers.removes())),
+        )
+        .with_extends(extends)
+        .with_require_style(
+            polyfill.require_style().clone(),
+            output_root(&manifest).to_owned(),
+        )
+        .with_sandbox(manifest.sandbox().clone());
+
+        let mut file_directives = HashMap::new();
+        for (path, directives) in collect_directives(manifest.input()).await? {
+            file_directives.insert(output_file_path(&manifest, &path)?, directives);
+        }
+        injector = injector.with_directives(file_directives);
+        if polyfill.mode() == PolyfillMode::Inline {
+            let source = fs::read_to_string(&module_path).await?;
+            fs::remove_file(&module_path).await?;
+            injector = injector.with_inline_source(source);
+        }
+
+        for source_path in &output_files {
+            injector.inject(source_path).await?;
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(modifiers: &str, minify: bool) -> Manifest {
+        Manifest {
+            modifiers: toml::from_str(modifiers).unwrap(),
+            minify,
+            ..Default::default()
+        }
+    }
+
+    fn names(pipeline: &[(ModifierPhase, &str)], phase: ModifierPhase) -> Vec<String> {
+        pipeline
+            .iter()
+            .filter(|(modifier_phase, _)| *modifier_phase == phase)
+            .map(|(_, name)| name.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn ordered_modifier_runs_between_defaults() {
+        let manifest = manifest(r#"custom = { order = 35, phase = "lower" }"#, false);
+        let pipeline = pipeline(&manifest, &ModifierRegistry::default()).unwrap();
+        let lower = names(&pipeline, ModifierPhase::Lower);
+
+        let custom = lower.iter().position(|name| name == "custom").unwrap();
+        assert_eq!(lower[custom - 1], "remove_types");
+        assert_eq!(lower[custom + 1], "remove_if_expression");
+        let remove_generalized_iteration = lower
+            .iter()
+            .position(|name| name == "remove_generalized_iteration")
+            .unwrap();
+        assert!(custom < remove_generalized_iteration);
+    }
+
+    #[test]
+    fn unordered_modifiers_run_after_ordered_ones() {
+        let manifest = manifest(
+            r#"
+            unordered_b = true
+            unordered_a = { phase = "minify" }
+            custom = { order = 5, phase = "minify" }
+            unordered_c = true
+            "#,
+            true,
+        );
+        let pipeline = pipeline(&manifest, &ModifierRegistry::default()).unwrap();
+
+        let lower = names(&pipeline, ModifierPhase::Lower);
+        assert_eq!(
+            lower[DEFAULT_LUAU_TO_LUA_MODIFIERS.len()..],
+            ["unordered_b", "unordered_c"]
+        );
+        let minify = names(&pipeline, ModifierPhase::Minify);
+        assert_eq!(minify[0], "custom");
+        assert_eq!(
+            minify[1..=DEFAULT_MINIFYING_MODIFIERS.len()],
+            DEFAULT_MINIFYING_MODIFIERS
+        );
+        assert_eq!(minify.last().unwrap(), "unordered_a");
+    }
+}
@@ -1,26 +1,109 @@
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+    cell::Cell,
+    collections::HashSet,
+    ffi::OsStr,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::Once,
+};
 
 use anyhow::{anyhow, Result};
+use async_walkdir::WalkDir;
 use full_moon::{
-    ast::{Ast, Expression, Field, LastStmt},
-    tokenizer::TokenKind,
+    ast::{
+        Ast, Block, Expression, Field, Index, LastStmt, Prefix, Stmt, Suffix, TableConstructor, Var,
+    },
+    tokenizer::{TokenReference, TokenType},
+    LuaVersion,
 };
+use futures_lite::stream::StreamExt;
 use tokio::fs;
 
 use crate::TargetVersion;
 
+#[allow(clippy::large_enum_variant)]
 pub enum ParseTarget {
     FullMoonAst(Ast),
     File(PathBuf, TargetVersion),
 }
 
-pub(crate) async fn parse_file(path: &PathBuf, target_version: &TargetVersion) -> Result<Ast> {
+pub async fn parse_file(path: &PathBuf, target_version: &TargetVersion) -> Result<Ast> {
     let code = fs::read_to_string(path).await?;
-    let ast = full_moon::parse_fallible(code.as_str(), target_version.to_lua_version().clone())
-        .into_result()
-        .map_err(|errors| anyhow!("full_moon parsing error: {:?}", errors))?;
+    parse_code(&code, target_version.to_lua_version())
+}
+
+thread_local! {
+    static PARSING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Parses code, reporting a panic of the parser (e.g. Lua 5.3 operators parsed as Luau) as an error.
+pub fn parse_code(code: &str, lua_version: LuaVersion) -> Result<Ast> {
+    static QUIET_PARSER_PANICS: Once = Once::new();
+    QUIET_PARSER_PANICS.call_once(|| {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !PARSING.get() {
+                hook(info);
+            }
+        }));
+    });
+
+    PARSING.set(true);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        full_moon::parse_fallible(code, lua_version).into_result()
+    }));
+    PARSING.set(false);
+
+    result
+        .map_err(|_| anyhow!("full_moon parsing error: parser panicked"))?
+        .map_err(|errors| anyhow!("full_moon parsing error: {:?}", errors))
+}
+
+/// Collects lua and luau files in the given directory. A file path is returned as is.
+pub async fn collect_lua_files(path: &PathBuf) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.clone()]);
+    }
+
+    let mut files = Vec::new();
+    let mut entries = WalkDir::new(path);
+    while let Some(entry) = entries.next().await {
+        let path = entry?.path();
+        if !matches!(
+            path.extension().and_then(OsStr::to_str),
+            Some("lua") | Some("luau")
+        ) {
+            continue;
+        }
+        files.push(path);
+    }
+    Ok(files)
+}
 
-    Ok(ast)
+/// Copies a directory recursively, skipping entries with the given names.
+///
+/// Fails if `to` is inside `from`.
+pub async fn copy_dir(from: &Path, to: &Path, skip: &[&str]) -> Result<()> {
+    if std::path::absolute(to)?.starts_with(std::path::absolute(from)?) {
+        return Err(anyhow!("Cannot copy {:?} into itself ({:?})", from, to));
+    }
+    let mut dirs = vec![(from.to_owned(), to.to_owned())];
+    while let Some((from, to)) = dirs.pop() {
+        fs::create_dir_all(&to).await?;
+        let mut entries = fs::read_dir(&from).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            if skip.iter().any(|skip| name == *skip) {
+                continue;
+            }
+            if entry.file_type().await?.is_dir() {
+                dirs.push((entry.path(), to.join(name)));
+            } else {
+                fs::copy(entry.path(), to.join(name)).await?;
+            }
+        }
+    }
+    Ok(())
 }
 
 /// Gets exports of lua modules by parsing last statement's table constructor.
@@ -29,63 +112,197 @@ pub async fn get_exports_from_last_stmt(target: &ParseTarget) -> Result<Option<H
         ParseTarget::FullMoonAst(ast) => ast,
         ParseTarget::File(path, target_version) => &parse_file(path, target_version).await?,
     };
-    let block = ast.nodes();
+    Ok(get_exports_from_ast(ast))
+}
 
-    if let Some(exports) = block
-        .last_stmt()
-        .and_then(|last_stmt| match last_stmt {
-            LastStmt::Return(return_stmt) => return_stmt.returns().first(),
+/// Gets the name of an identifier token.
+fn identifier_name(token: &TokenReference) -> Option<String> {
+    match token.token_type() {
+        TokenType::Identifier { identifier } => Some(identifier.to_string()),
+        _ => None,
+    }
+}
+
+/// Gets the value of a string literal expression without its quotes.
+fn string_literal(expression: &Expression) -> Option<String> {
+    match expression {
+        Expression::String(token) => match token.token_type() {
+            TokenType::StringLiteral { literal, .. } => Some(literal.to_string()),
             _ => None,
-        })
-        .and_then(|first_return| match first_return.value() {
-            Expression::TableConstructor(table_constructor) => Some(table_constructor),
+        },
+        _ => None,
+    }
+}
+
+/// Gets keys of a table constructor's named and string keyed fields.
+fn table_constructor_keys(table_constructor: &TableConstructor) -> HashSet<String> {
+    let mut keys = HashSet::new();
+    for field in table_constructor.fields() {
+        let key = match field {
+            Field::ExpressionKey { key, .. } => string_literal(key),
+            Field::NameKey { key, .. } => identifier_name(key),
             _ => None,
-        })
-        .map(|table_constructor| {
-            let mut exports: HashSet<String> = HashSet::new();
-            for field in table_constructor.fields() {
-                if let Some(new_export) = match field {
-                    Field::ExpressionKey {
-                        brackets: _,
-                        key,
-                        equal: _,
-                        value: _,
-                    } => {
-                        if let Expression::String(string_token) = key {
-                            let string_token = string_token.token();
-                            if let TokenKind::StringLiteral = string_token.token_kind() {
-                                log::debug!("[get_exports_from_last_stmt] ExpressionKey token kind: {:?} string: {}", string_token.token_kind(), string_token.to_string());
-                                Some(string_token.to_string().trim().to_owned())
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
+        };
+        if let Some(key) = key {
+            log::debug!("[get_exports_from_last_stmt] table key: {}", key);
+            keys.insert(key);
+        }
+    }
+    keys
+}
+
+/// Gets the field name of `module.field` or `module["field"]`.
+fn module_field_name(var: &Var, module: &str) -> Option<String> {
+    let Var::Expression(var_expression) = var else {
+        return None;
+    };
+    let Prefix::Name(prefix) = var_expression.prefix() else {
+        return None;
+    };
+    if identifier_name(prefix).as_deref() != Some(module) {
+        return None;
+    }
+
+    let mut suffixes = var_expression.suffixes();
+    match (suffixes.next(), suffixes.next()) {
+        (Some(Suffix::Index(Index::Dot { name, .. })), None) => identifier_name(name),
+        (Some(Suffix::Index(Index::Brackets { expression, .. })), None) => {
+            string_literal(expression)
+        }
+        _ => None,
+    }
+}
+
+/// Collects the fields of a module table declared as a local in the block.
+///
+/// Supports `local M = {}`, `M.foo = ...`, `M["foo"] = ...` and `function M.foo() end`.
+fn module_table_keys(block: &Block, module: &str) -> Option<HashSet<String>> {
+    let mut keys: Option<HashSet<String>> = None;
+    for stmt in block.stmts() {
+        match stmt {
+            Stmt::LocalAssignment(local_assignment) => {
+                let Some(index) = local_assignment
+                    .names()
+                    .iter()
+                    .position(|name| identifier_name(name).as_deref() == Some(module))
+                else {
+                    continue;
+                };
+                // a redeclaration shadows the previous module table
+                keys = match local_assignment.expressions().iter().nth(index) {
+                    Some(Expression::TableConstructor(table_constructor)) => {
+                        Some(table_constructor_keys(table_constructor))
+                    }
+                    _ => None,
+                };
+            }
+            Stmt::Assignment(assignment) => {
+                if let Some(keys) = &mut keys {
+                    for var in assignment.variables() {
+                        if let Some(key) = module_field_name(var, module) {
+                            keys.insert(key);
                         }
                     }
-                    Field::NameKey {
-                        key,
-                        equal: _,
-                        value: _,
-                    } => {
-                        let key = key.token();
-                        if let TokenKind::Identifier = key.token_kind() {
-                            log::debug!("[get_exports_from_last_stmt] NameKey token kind: {:?} string: {}", key.token_kind(), key.to_string());
-                            Some(key.to_string().trim().to_owned())
-                        } else {
-                            None
+                }
+            }
+            Stmt::FunctionDeclaration(function_declaration) => {
+                let name = function_declaration.name();
+                let mut names = name.names().iter();
+                if let (Some(keys), Some(first), Some(second), None, None) = (
+                    &mut keys,
+                    names.next(),
+                    names.next(),
+                    names.next(),
+                    name.method_name(),
+                ) {
+                    if identifier_name(first).as_deref() == Some(module) {
+                        if let Some(key) = identifier_name(second) {
+                            keys.insert(key);
                         }
                     }
-                    _ => None,
-                } {
-                    exports.insert(new_export);
                 }
             }
-            exports
-        })
-    {
-        return Ok(Some(exports));
+            _ => {}
+        }
     }
+    keys
+}
+
+/// Gets exports of a parsed lua module from its last statement.
+///
+/// The module may return a table constructor, or a local table whose fields are assigned in the
+/// module's top-level block.
+pub fn get_exports_from_ast(ast: &Ast) -> Option<HashSet<String>> {
+    let block = ast.nodes();
 
-    Ok(None)
+    let first_return = match block.last_stmt()? {
+        LastStmt::Return(return_stmt) => return_stmt.returns().first()?,
+        _ => return None,
+    };
+    match first_return.value() {
+        Expression::TableConstructor(table_constructor) => {
+            Some(table_constructor_keys(table_constructor))
+        }
+        Expression::Var(Var::Name(name)) => module_table_keys(block, &identifier_name(name)?),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exports(code: &str) -> Option<HashSet<String>> {
+        let ast = full_moon::parse_fallible(code, TargetVersion::Luau.to_lua_version())
+            .into_result()
+            .unwrap();
+        get_exports_from_ast(&ast)
+    }
+
+    #[test]
+    fn parse_code_reports_parser_panics() {
+        assert!(parse_code("local a = 1 & 2", TargetVersion::Luau.to_lua_version()).is_err());
+        assert!(parse_code("local a = 1 & 2", TargetVersion::Lua53.to_lua_version()).is_ok());
+    }
+
+    fn names(names: &[&str]) -> Option<HashSet<String>> {
+        Some(names.iter().map(|name| name.to_string()).collect())
+    }
+
+    #[test]
+    fn exports_of_table_constructor() {
+        assert_eq!(
+            exports("return { foo = 1, [\"bar\"] = 2, 3, [4] = 5 }"),
+            names(&["foo", "bar"])
+        );
+    }
+
+    #[test]
+    fn exports_of_module_table() {
+        let code = r#"
+local M = { a = 1 }
+M.b = 2
+M["c"] = 3
+function M.d() end
+function M:e() end
+other.f = 4
+return M
+"#;
+        assert_eq!(exports(code), names(&["a", "b", "c", "d"]));
+    }
+
+    #[test]
+    fn exports_of_redeclared_module_table() {
+        assert_eq!(
+            exports("local M = { a = 1 }\nlocal M = {}\nM.b = 2\nreturn M"),
+            names(&["b"])
+        );
+        assert_eq!(exports("local M = make()\nM.a = 1\nreturn M"), None);
+    }
+
+    #[test]
+    fn no_exports_without_returned_table() {
+        assert_eq!(exports("local x = 1"), None);
+        assert_eq!(exports("return 1"), None);
+    }
 }